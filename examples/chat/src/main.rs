@@ -33,6 +33,12 @@ async fn main() {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
+    let listener = TcpListener::bind("localhost:3000").await.unwrap();
+
+    axum::serve(listener, app()).await.unwrap()
+}
+
+fn app() -> Router {
     let (tx, _rx) = broadcast::channel(16);
 
     let app_state = Arc::new(AppState {
@@ -40,14 +46,10 @@ async fn main() {
         tx,
     });
 
-    let app = Router::new()
+    Router::new()
         .route("/", get(index))
         .route("/ws", get(websocket_handler))
-        .with_state(app_state);
-
-    let listener = TcpListener::bind("localhost:3000").await.unwrap();
-
-    axum::serve(listener, app).await.unwrap()
+        .with_state(app_state)
 }
 
 async fn websocket_handler(
@@ -136,3 +138,50 @@ fn check_username(state: &AppState, username: &mut String, name: &str) {
 async fn index() -> Html<String> {
     Html(include_str!("../index.html").to_string())
 }
+
+#[cfg(test)]
+mod tests {
+    use ws_harness::TestServer;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_join_and_leave() {
+        let server = TestServer::spawn(app()).await;
+
+        let mut alice = server.connect("/ws").await;
+        alice.send_text("alice").await;
+        alice.expect_text("alice joined the chat").await;
+
+        let mut bob = server.connect("/ws").await;
+        bob.send_text("bob").await;
+        bob.expect_text("bob joined the chat").await;
+        alice.expect_text("bob joined the chat").await;
+
+        bob.send_text("hi").await;
+        bob.expect_text("bob: hi").await;
+        alice.expect_text("bob: hi").await;
+
+        bob.close().await;
+        alice.expect_text("bob left the chat").await;
+        alice.expect_text("1 users in chat").await;
+    }
+
+    #[tokio::test]
+    async fn test_username_taken() {
+        let server = TestServer::spawn(app()).await;
+
+        let mut alice = server.connect("/ws").await;
+        alice.send_text("alice").await;
+        alice.expect_text("alice joined the chat").await;
+
+        let mut impostor = server.connect("/ws").await;
+        impostor.send_text("alice").await;
+        impostor.expect_text("Username already taken").await;
+        impostor.expect_closed().await;
+
+        // The first alice is still in the chat, and nobody else joined
+        alice.send_text("still here").await;
+        alice.expect_text("alice: still here").await;
+    }
+}
@@ -1,22 +1,32 @@
-use std::{
-    collections::HashMap,
-    sync::{
-        atomic::{AtomicU64, Ordering},
-        Arc, Mutex,
-    },
-};
+mod panic;
+mod problem;
+mod store;
+mod validation;
+
+use std::sync::Arc;
 
 use axum::{
-    extract::{rejection::JsonRejection, FromRequest, MatchedPath, Request, State},
+    extract::{
+        rejection::{ExtensionRejection, JsonRejection, PathRejection, QueryRejection},
+        FromRequest, FromRequestParts, MatchedPath, Request, State,
+    },
     http::StatusCode,
+    middleware,
     response::IntoResponse,
-    routing::post,
+    routing::get,
     Router,
 };
-use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+use problem::Problem;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use store::{FileUserStore, InMemoryUserStore, NewUser, StoreError, UserStore};
 use tokio::net::TcpListener;
-use tower_http::trace::TraceLayer;
+use tower_http::{
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, RequestId, SetRequestIdLayer},
+    trace::TraceLayer,
+};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use validation::{FieldError, Validate};
 
 #[tokio::main]
 async fn main() {
@@ -28,8 +38,39 @@ async fn main() {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let app = Router::new()
-        .route("/users", post(create_user))
+    panic::install_hook();
+
+    let app = app(AppState {
+        users: user_store(),
+    });
+
+    let listener = TcpListener::bind("localhost:3000").await.unwrap();
+
+    tracing::debug!("listening on {}", listener.local_addr().unwrap());
+
+    axum::serve(listener, app).await.unwrap();
+}
+
+fn app(state: AppState) -> Router {
+    with_middleware(routes()).with_state(state)
+}
+
+fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/users", get(list_users).post(create_user))
+        .route(
+            "/users/{id}",
+            get(get_user).put(update_user).delete(delete_user),
+        )
+}
+
+// Error handling shared by every route, kept apart from `routes` so tests can add their own
+fn with_middleware(router: Router<AppState>) -> Router<AppState> {
+    router
+        .fallback(|| async { Problem::new(StatusCode::NOT_FOUND) })
+        .method_not_allowed_fallback(|| async { Problem::new(StatusCode::METHOD_NOT_ALLOWED) })
+        .layer(panic::layer())
+        .layer(middleware::from_fn(problem::problem_context))
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(|req: &Request| {
@@ -38,53 +79,131 @@ async fn main() {
 
                     let matched_path = req.extensions().get::<MatchedPath>().map(|m| m.as_str());
 
-                    tracing::debug_span!("request", %method, %uri, matched_path)
+                    // Set by `SetRequestIdLayer` below, so always present
+                    let request_id = req
+                        .extensions()
+                        .get::<RequestId>()
+                        .and_then(|id| id.header_value().to_str().ok());
+
+                    tracing::debug_span!("request", %method, %uri, matched_path, request_id)
                 })
                 .on_failure(()),
         )
-        .with_state(AppState::default());
-
-    let listener = TcpListener::bind("localhost:3000").await.unwrap();
-
-    tracing::debug!("listening on {}", listener.local_addr().unwrap());
+        // Echo the request id back, so clients can quote it when reporting an error
+        .layer(PropagateRequestIdLayer::x_request_id())
+        // Keep the caller's `x-request-id` or generate a new one. This has to run before
+        // `TraceLayer`, so the id ends up on the request span
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+}
 
-    axum::serve(listener, app).await.unwrap();
+// Users are kept in memory, unless `USERS_FILE` points to a JSON file to persist them in
+fn user_store() -> Arc<dyn UserStore> {
+    match std::env::var("USERS_FILE") {
+        Ok(path) => Arc::new(FileUserStore::open(path).expect("failed to open users file")),
+        Err(_) => Arc::new(InMemoryUserStore::default()),
+    }
 }
 
-#[derive(Debug, Default, Clone)]
+#[derive(Clone)]
 struct AppState {
-    next_id: Arc<AtomicU64>,
-    users: Arc<Mutex<HashMap<u64, User>>>,
+    users: Arc<dyn UserStore>,
 }
 
 #[derive(Deserialize)]
 struct UserParams {
     name: String,
+    email: String,
+}
+
+validation::rules! {
+    UserParams {
+        name: length(1, 64);
+        email: length(3, 254), email;
+    }
 }
 
-#[derive(Debug, Serialize, Clone)]
+impl From<UserParams> for NewUser {
+    fn from(params: UserParams) -> Self {
+        NewUser {
+            name: params.name,
+            email: params.email,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 struct User {
     id: u64,
     name: String,
-    created_at: std::time::SystemTime,
+    email: String,
+    // Serialized as RFC 3339
+    created_at: DateTime<Utc>,
 }
 
 async fn create_user(
     State(state): State<AppState>,
-    AppJson(params): AppJson<UserParams>,
+    ValidatedJson(params): ValidatedJson<UserParams>,
+) -> Result<(StatusCode, AppJson<User>), AppError> {
+    let user = state.users.create(params.into())?;
+
+    Ok((StatusCode::CREATED, AppJson(user)))
+}
+
+async fn get_user(
+    State(state): State<AppState>,
+    AppPath(id): AppPath<u64>,
 ) -> Result<AppJson<User>, AppError> {
-    let id = state.next_id.fetch_add(1, Ordering::SeqCst);
-    let created_at = std::time::SystemTime::now();
+    Ok(AppJson(state.users.get(id)?))
+}
 
-    let user = User {
-        created_at,
-        id,
-        name: params.name,
-    };
+async fn update_user(
+    State(state): State<AppState>,
+    AppPath(id): AppPath<u64>,
+    ValidatedJson(params): ValidatedJson<UserParams>,
+) -> Result<AppJson<User>, AppError> {
+    Ok(AppJson(state.users.update(id, params.into())?))
+}
 
-    state.users.lock().unwrap().insert(id, user.clone());
+async fn delete_user(
+    State(state): State<AppState>,
+    AppPath(id): AppPath<u64>,
+) -> Result<StatusCode, AppError> {
+    state.users.delete(id)?;
 
-    Ok(AppJson(user))
+    Ok(StatusCode::NO_CONTENT)
+}
+
+const DEFAULT_PAGE_SIZE: usize = 20;
+const MAX_PAGE_SIZE: usize = 100;
+
+#[derive(Deserialize)]
+struct ListParams {
+    // `next_cursor` of the previous page
+    cursor: Option<u64>,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct UserPage {
+    users: Vec<User>,
+    next_cursor: Option<u64>,
+}
+
+async fn list_users(
+    State(state): State<AppState>,
+    AppQuery(params): AppQuery<ListParams>,
+) -> Result<AppJson<UserPage>, AppError> {
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+
+    let page = state.users.list(params.cursor, limit)?;
+
+    Ok(AppJson(UserPage {
+        users: page.users,
+        next_cursor: page.next_cursor,
+    }))
 }
 
 // derive FromRequest macro implements the FromRequest trait for AppJson
@@ -105,31 +224,99 @@ where
     }
 }
 
+// AppJson that reports wrong fields by their path and also runs the `Validate` checks of the
+// body, rejecting it with every failed field
+struct ValidatedJson<T>(T);
+
+impl<T, S> FromRequest<S> for ValidatedJson<T>
+where
+    T: DeserializeOwned + Validate,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        // Content type and syntax errors are still rejected by `Json`
+        let AppJson(body) = AppJson::<serde_json::Value>::from_request(req, state).await?;
+
+        let value = serde_path_to_error::deserialize(body)
+            .map_err(|err| AppError::Validation(vec![field_error(&err)]))?;
+
+        validation::validate(&value).map_err(AppError::Validation)?;
+
+        Ok(ValidatedJson(value))
+    }
+}
+
+// Same as AppJson, but for the extractors that only need the request parts
+#[derive(FromRequestParts)]
+#[from_request(via(axum::extract::Path), rejection(AppError))]
+struct AppPath<T>(T);
+
+#[derive(FromRequestParts)]
+#[from_request(via(axum::extract::Query), rejection(AppError))]
+struct AppQuery<T>(T);
+
 enum AppError {
     JsonRejection(JsonRejection),
-    TimeError(std::time::SystemTimeError),
+    PathRejection(PathRejection),
+    QueryRejection(QueryRejection),
+    // e.g. state shared through `Extension` that was never added to the router
+    ExtensionRejection(ExtensionRejection),
+    // The body is well-formed JSON, but some of its fields are wrong
+    Validation(Vec<FieldError>),
+    UserNotFound(u64),
+    EmailTaken(String),
+    Storage(std::io::Error),
 }
 
-impl IntoResponse for AppError {
-    fn into_response(self) -> axum::response::Response {
-        #[derive(Serialize)]
-        struct Error {
-            message: String,
-        }
+impl From<AppError> for Problem {
+    fn from(error: AppError) -> Self {
+        match error {
+            AppError::JsonRejection(rejection) => Problem::new(rejection.status())
+                .with_type("/problems/invalid-body")
+                .with_title("Invalid request body")
+                .with_detail(rejection.body_text()),
+            AppError::PathRejection(rejection) => Problem::new(rejection.status())
+                .with_type("/problems/invalid-path")
+                .with_title("Invalid path parameters")
+                .with_detail(rejection.body_text()),
+            AppError::QueryRejection(rejection) => Problem::new(rejection.status())
+                .with_type("/problems/invalid-query")
+                .with_title("Invalid query string")
+                .with_detail(rejection.body_text()),
+            AppError::ExtensionRejection(rejection) => {
+                // A missing extension is a bug in the app, not in the request
+                tracing::error!(error = %rejection.body_text(), "missing request extension");
 
-        let (status, message) = match self {
-            AppError::JsonRejection(rejection) => (rejection.status(), rejection.body_text()),
-            AppError::TimeError(err) => {
-                tracing::error!(%err, "error from system time");
+                Problem::new(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+            AppError::Validation(errors) => Problem::new(StatusCode::UNPROCESSABLE_ENTITY)
+                .with_type("/problems/validation-failed")
+                .with_title("Invalid request fields")
+                .with_detail(format!("{} field(s) failed validation", errors.len()))
+                .with_extension("errors", errors),
+            AppError::UserNotFound(id) => Problem::new(StatusCode::NOT_FOUND)
+                .with_type("/problems/user-not-found")
+                .with_title("User not found")
+                .with_detail(format!("No user with id {id}"))
+                .with_extension("user_id", id),
+            AppError::EmailTaken(email) => Problem::new(StatusCode::CONFLICT)
+                .with_type("/problems/email-taken")
+                .with_title("Email already in use")
+                .with_detail(format!("Another user already has the email {email}")),
+            AppError::Storage(err) => {
+                tracing::error!(%err, "user storage failed");
 
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "Internal server error".to_string(),
-                )
+                Problem::new(StatusCode::INTERNAL_SERVER_ERROR)
             }
-        };
+        }
+    }
+}
 
-        (status, axum::Json(Error { message })).into_response()
+impl IntoResponse for AppError {
+    fn into_response(self) -> axum::response::Response {
+        Problem::from(self).into_response()
     }
 }
 
@@ -138,3 +325,501 @@ impl From<JsonRejection> for AppError {
         Self::JsonRejection(rejection)
     }
 }
+
+fn field_error(err: &serde_path_to_error::Error<serde_json::Error>) -> FieldError {
+    // Errors of a `Value` deserializer carry no position, so this is just the message
+    let message = err.inner().to_string();
+    let path = err.path().to_string();
+
+    // A missing field is reported on its parent, point at the field itself instead
+    if let Some(field) = message
+        .strip_prefix("missing field `")
+        .and_then(|rest| rest.strip_suffix('`'))
+    {
+        let field = match path.as_str() {
+            "." => field.to_string(),
+            parent => format!("{parent}.{field}"),
+        };
+
+        return FieldError::new(field, "is required");
+    }
+
+    FieldError::new(path, message)
+}
+
+impl From<StoreError> for AppError {
+    fn from(err: StoreError) -> Self {
+        match err {
+            StoreError::NotFound(id) => Self::UserNotFound(id),
+            StoreError::EmailTaken(email) => Self::EmailTaken(email),
+            StoreError::Io(err) => Self::Storage(err),
+        }
+    }
+}
+
+impl From<PathRejection> for AppError {
+    fn from(rejection: PathRejection) -> Self {
+        Self::PathRejection(rejection)
+    }
+}
+
+impl From<QueryRejection> for AppError {
+    fn from(rejection: QueryRejection) -> Self {
+        Self::QueryRejection(rejection)
+    }
+}
+
+impl From<ExtensionRejection> for AppError {
+    fn from(rejection: ExtensionRejection) -> Self {
+        Self::ExtensionRejection(rejection)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{routing::post, Extension};
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    // Serves `routes` with the app's middleware on an ephemeral port
+    async fn serve(routes: Router<AppState>, users: Arc<dyn UserStore>) -> String {
+        let app = with_middleware(routes).with_state(AppState { users });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        url
+    }
+
+    async fn spawn_app() -> String {
+        serve(routes(), Arc::new(InMemoryUserStore::default())).await
+    }
+
+    // The body of a problem response, which must have `status`
+    async fn problem(response: reqwest::Response, status: StatusCode) -> Value {
+        assert_eq!(response.status(), status);
+        assert_eq!(
+            response.headers()["content-type"],
+            "application/problem+json"
+        );
+
+        let body = response.json::<Value>().await.unwrap();
+
+        assert_eq!(body["status"], status.as_u16());
+
+        body
+    }
+
+    #[derive(Deserialize)]
+    struct Order {
+        #[allow(dead_code)]
+        items: Vec<Item>,
+    }
+
+    #[derive(Deserialize)]
+    struct Item {
+        #[allow(dead_code)]
+        quantity: u32,
+    }
+
+    impl Validate for Order {
+        fn validate(&self, _: &mut validation::Validator) {}
+    }
+
+    async fn create_order(ValidatedJson(_): ValidatedJson<Order>) -> StatusCode {
+        StatusCode::CREATED
+    }
+
+    async fn panicking_handler() -> StatusCode {
+        panic!("deliberate panic")
+    }
+
+    #[derive(Clone)]
+    struct Missing;
+
+    #[derive(FromRequestParts)]
+    #[from_request(via(Extension), rejection(AppError))]
+    struct AppExtension<T>(T);
+
+    async fn missing_extension(AppExtension(_): AppExtension<Missing>) {}
+
+    #[tokio::test]
+    async fn test_json_rejection() {
+        let url = spawn_app().await;
+        let client = reqwest::Client::new();
+
+        let response = client
+            .post(format!("{url}/users"))
+            .header("content-type", "application/json")
+            .body("{\"name\": ")
+            .send()
+            .await
+            .unwrap();
+
+        let body = problem(response, StatusCode::BAD_REQUEST).await;
+
+        assert_eq!(body["type"], "/problems/invalid-body");
+        assert_eq!(body["title"], "Invalid request body");
+        assert_eq!(body["instance"], "/users");
+        assert!(body["detail"].as_str().unwrap().contains("EOF"));
+
+        let response = client
+            .post(format!("{url}/users"))
+            .body("name=ferris")
+            .send()
+            .await
+            .unwrap();
+
+        let body = problem(response, StatusCode::UNSUPPORTED_MEDIA_TYPE).await;
+
+        assert_eq!(body["type"], "/problems/invalid-body");
+    }
+
+    #[tokio::test]
+    async fn test_path_rejection() {
+        let url = spawn_app().await;
+
+        let response = reqwest::get(format!("{url}/users/ferris")).await.unwrap();
+
+        let body = problem(response, StatusCode::BAD_REQUEST).await;
+
+        assert_eq!(body["type"], "/problems/invalid-path");
+        assert_eq!(body["title"], "Invalid path parameters");
+        assert_eq!(body["instance"], "/users/ferris");
+        assert!(body["detail"].as_str().unwrap().contains("ferris"));
+    }
+
+    #[tokio::test]
+    async fn test_query_rejection() {
+        let url = spawn_app().await;
+
+        let response = reqwest::get(format!("{url}/users?limit=many"))
+            .await
+            .unwrap();
+
+        let body = problem(response, StatusCode::BAD_REQUEST).await;
+
+        assert_eq!(body["type"], "/problems/invalid-query");
+        assert_eq!(body["title"], "Invalid query string");
+        assert!(body["detail"].as_str().unwrap().contains("limit"));
+    }
+
+    #[tokio::test]
+    async fn test_extension_rejection() {
+        let routes = routes().route("/missing", post(missing_extension));
+        let url = serve(routes, Arc::new(InMemoryUserStore::default())).await;
+
+        let response = reqwest::Client::new()
+            .post(format!("{url}/missing"))
+            .send()
+            .await
+            .unwrap();
+
+        let body = problem(response, StatusCode::INTERNAL_SERVER_ERROR).await;
+
+        // Nothing about the app's internals leaks to the client
+        assert_eq!(body["type"], "about:blank");
+        assert_eq!(body["title"], "Internal Server Error");
+        assert!(body.get("detail").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_fallbacks() {
+        let url = spawn_app().await;
+        let client = reqwest::Client::new();
+
+        let response = client.get(format!("{url}/nope")).send().await.unwrap();
+
+        let body = problem(response, StatusCode::NOT_FOUND).await;
+
+        assert_eq!(body["title"], "Not Found");
+        assert_eq!(body["instance"], "/nope");
+
+        let response = client.patch(format!("{url}/users")).send().await.unwrap();
+
+        problem(response, StatusCode::METHOD_NOT_ALLOWED).await;
+    }
+
+    #[tokio::test]
+    async fn test_validation_errors() {
+        let url = spawn_app().await;
+
+        let response = reqwest::Client::new()
+            .post(format!("{url}/users"))
+            .json(&json!({ "name": "", "email": "ferris at example.com" }))
+            .send()
+            .await
+            .unwrap();
+
+        let body = problem(response, StatusCode::UNPROCESSABLE_ENTITY).await;
+
+        assert_eq!(body["type"], "/problems/validation-failed");
+        assert_eq!(
+            body["errors"],
+            json!([
+                { "field": "name", "message": "must be between 1 and 64 characters long" },
+                { "field": "email", "message": "must be a valid email address" },
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_serde_error_paths() {
+        let routes = routes().route("/orders", post(create_order));
+        let url = serve(routes, Arc::new(InMemoryUserStore::default())).await;
+        let client = reqwest::Client::new();
+
+        let errors = |body: Value| {
+            let client = client.clone();
+            let url = url.clone();
+
+            async move {
+                let response = client
+                    .post(format!("{url}/orders"))
+                    .json(&body)
+                    .send()
+                    .await
+                    .unwrap();
+
+                problem(response, StatusCode::UNPROCESSABLE_ENTITY).await["errors"].clone()
+            }
+        };
+
+        assert_eq!(
+            errors(json!({ "items": [{ "quantity": 1 }, { "quantity": "two" }] })).await,
+            json!([{
+                "field": "items[1].quantity",
+                "message": "invalid type: string \"two\", expected u32",
+            }])
+        );
+        assert_eq!(
+            errors(json!({ "items": [{}] })).await,
+            json!([{ "field": "items[0].quantity", "message": "is required" }])
+        );
+        assert_eq!(
+            errors(json!({})).await,
+            json!([{ "field": "items", "message": "is required" }])
+        );
+
+        let response = client
+            .post(format!("{url}/orders"))
+            .json(&json!({ "items": [{ "quantity": 1 }] }))
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn test_users() {
+        let url = spawn_app().await;
+        let client = reqwest::Client::new();
+
+        let ferris = json!({ "name": "Ferris", "email": "ferris@example.com" });
+
+        let response = client
+            .post(format!("{url}/users"))
+            .json(&ferris)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let user = response.json::<Value>().await.unwrap();
+
+        assert_eq!(user["id"], 0);
+        assert_eq!(user["name"], "Ferris");
+
+        let response = client.get(format!("{url}/users/0")).send().await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.json::<Value>().await.unwrap(), user);
+
+        let response = client
+            .put(format!("{url}/users/0"))
+            .json(&json!({ "name": "Ferris the Crab", "email": "ferris@example.com" }))
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let updated = response.json::<Value>().await.unwrap();
+
+        assert_eq!(updated["name"], "Ferris the Crab");
+        assert_eq!(updated["created_at"], user["created_at"]);
+
+        let response = client
+            .delete(format!("{url}/users/0"))
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        // Gone for every route that takes an id
+        for request in [
+            client.get(format!("{url}/users/0")),
+            client.put(format!("{url}/users/0")).json(&ferris),
+            client.delete(format!("{url}/users/0")),
+        ] {
+            let body = problem(request.send().await.unwrap(), StatusCode::NOT_FOUND).await;
+
+            assert_eq!(body["type"], "/problems/user-not-found");
+            assert_eq!(body["title"], "User not found");
+            assert_eq!(body["user_id"], 0);
+            assert_eq!(body["instance"], "/users/0");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_email_taken() {
+        let url = spawn_app().await;
+        let client = reqwest::Client::new();
+
+        for name in ["ferris", "corro"] {
+            let response = client
+                .post(format!("{url}/users"))
+                .json(&json!({ "name": name, "email": format!("{name}@example.com") }))
+                .send()
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), StatusCode::CREATED);
+        }
+
+        let taken = json!({ "name": "Ferris", "email": "ferris@example.com" });
+
+        for request in [
+            client.post(format!("{url}/users")).json(&taken),
+            client.put(format!("{url}/users/1")).json(&taken),
+        ] {
+            let body = problem(request.send().await.unwrap(), StatusCode::CONFLICT).await;
+
+            assert_eq!(body["type"], "/problems/email-taken");
+            assert_eq!(body["title"], "Email already in use");
+            assert!(body["detail"]
+                .as_str()
+                .unwrap()
+                .contains("ferris@example.com"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_list_users() {
+        let url = spawn_app().await;
+        let client = reqwest::Client::new();
+
+        for name in ["a", "b", "c", "d", "e"] {
+            client
+                .post(format!("{url}/users"))
+                .json(&json!({ "name": name, "email": format!("{name}@example.com") }))
+                .send()
+                .await
+                .unwrap();
+        }
+
+        let page = |query: &'static str| {
+            let request = client.get(format!("{url}/users?{query}"));
+
+            async move {
+                let response = request.send().await.unwrap();
+
+                assert_eq!(response.status(), StatusCode::OK);
+
+                let page = response.json::<Value>().await.unwrap();
+                let ids = page["users"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|user| user["id"].as_u64().unwrap())
+                    .collect::<Vec<_>>();
+
+                (ids, page["next_cursor"].clone())
+            }
+        };
+
+        assert_eq!(page("limit=2").await, (vec![0, 1], json!(1)));
+        assert_eq!(page("cursor=1&limit=2").await, (vec![2, 3], json!(3)));
+        assert_eq!(page("cursor=3&limit=2").await, (vec![4], Value::Null));
+
+        // Out of range limits are clamped
+        assert_eq!(page("limit=0").await, (vec![0], json!(0)));
+        assert_eq!(page("limit=1000").await, (vec![0, 1, 2, 3, 4], Value::Null));
+        assert_eq!(page("").await.0.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_generated_request_id() {
+        let url = spawn_app().await;
+
+        let response = reqwest::get(format!("{url}/users/42")).await.unwrap();
+
+        let request_id = response.headers()["x-request-id"]
+            .to_str()
+            .unwrap()
+            .to_owned();
+
+        assert_eq!(request_id.len(), 36, "not a UUID: {request_id}");
+
+        let body = problem(response, StatusCode::NOT_FOUND).await;
+
+        assert_eq!(body["type"], "/problems/user-not-found");
+        assert_eq!(body["request_id"], request_id);
+
+        // Every request gets its own id
+        let response = reqwest::get(format!("{url}/users/42")).await.unwrap();
+
+        assert_ne!(response.headers()["x-request-id"], request_id.as_str());
+    }
+
+    #[tokio::test]
+    async fn test_client_request_id() {
+        let url = spawn_app().await;
+
+        let response = reqwest::Client::new()
+            .get(format!("{url}/users/42"))
+            .header("x-request-id", "client-id-1")
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.headers()["x-request-id"], "client-id-1");
+
+        let body = problem(response, StatusCode::NOT_FOUND).await;
+
+        assert_eq!(body["request_id"], "client-id-1");
+    }
+
+    #[tokio::test]
+    async fn test_handler_panic() {
+        let routes = routes().route("/panic", get(panicking_handler));
+        let url = serve(routes, Arc::new(InMemoryUserStore::default())).await;
+
+        let client = reqwest::Client::new();
+
+        let response = client
+            .get(format!("{url}/panic"))
+            .header("x-request-id", "panic-test")
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.headers()["x-request-id"], "panic-test");
+
+        let body = problem(response, StatusCode::INTERNAL_SERVER_ERROR).await;
+
+        assert_eq!(body["instance"], "/panic");
+        assert_eq!(body["request_id"], "panic-test");
+
+        // The panic only took down the request, not the server
+        let response = client.get(format!("{url}/users")).send().await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,53 @@
+use std::{any::Any, backtrace::Backtrace, panic::PanicHookInfo};
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use tower_http::catch_panic::{CatchPanicLayer, ResponseForPanic};
+
+use crate::problem::Problem;
+
+/// Turns handler panics into a 500 problem response, instead of dropping the connection.
+///
+/// Has to be added inside `TraceLayer` and `problem_context`, so the response gets the request
+/// id like any other error and the panic is logged with the request span.
+pub fn layer() -> CatchPanicLayer<PanicResponse> {
+    CatchPanicLayer::custom(PanicResponse)
+}
+
+#[derive(Clone, Copy)]
+pub struct PanicResponse;
+
+impl ResponseForPanic for PanicResponse {
+    type ResponseBody = axum::body::Body;
+
+    // The panic itself was already logged by the hook, with its backtrace
+    fn response_for_panic(&mut self, _err: Box<dyn Any + Send + 'static>) -> Response {
+        Problem::new(StatusCode::INTERNAL_SERVER_ERROR).into_response()
+    }
+}
+
+/// Logs panics through `tracing` instead of printing them to stderr.
+///
+/// The hook runs on the panicking thread before unwinding, while the request span is still
+/// entered. That is the only place where the backtrace can still be captured.
+pub fn install_hook() {
+    std::panic::set_hook(Box::new(|info: &PanicHookInfo| {
+        let payload = payload(info.payload());
+        let location = info.location().map(ToString::to_string).unwrap_or_default();
+        let backtrace = Backtrace::force_capture();
+
+        tracing::error!(%payload, %location, %backtrace, "panicked");
+    }));
+}
+
+fn payload(payload: &(dyn Any + Send)) -> &str {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s
+    } else {
+        "Box<dyn Any>"
+    }
+}
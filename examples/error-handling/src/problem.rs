@@ -0,0 +1,135 @@
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use tower_http::request_id::RequestId;
+
+/// RFC 7807 problem details, rendered as `application/problem+json`.
+///
+/// `instance` and the `request_id` extension are filled in by the [`problem_context`] middleware,
+/// as `IntoResponse` has no access to the request.
+#[derive(Debug, Clone, Serialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub type_uri: String,
+    pub title: String,
+    #[serde(serialize_with = "serialize_status")]
+    pub status: StatusCode,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+    // Extension members are serialized next to the standard ones
+    #[serde(flatten)]
+    pub extensions: Map<String, Value>,
+}
+
+fn serialize_status<S: serde::Serializer>(status: &StatusCode, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_u16(status.as_u16())
+}
+
+impl Problem {
+    /// A problem without a specific type, titled after the status code.
+    pub fn new(status: StatusCode) -> Self {
+        Problem {
+            type_uri: "about:blank".to_string(),
+            title: status
+                .canonical_reason()
+                .unwrap_or("Unknown error")
+                .to_string(),
+            status,
+            detail: None,
+            instance: None,
+            extensions: Map::new(),
+        }
+    }
+
+    pub fn with_type(mut self, type_uri: impl Into<String>) -> Self {
+        self.type_uri = type_uri.into();
+        self
+    }
+
+    pub fn with_title(mut self, title: impl Into<String>) -> Self {
+        self.title = title.into();
+        self
+    }
+
+    pub fn with_detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    pub fn with_instance(mut self, instance: impl Into<String>) -> Self {
+        self.instance = Some(instance.into());
+        self
+    }
+
+    pub fn with_extension(mut self, key: impl Into<String>, value: impl Serialize) -> Self {
+        // Serializing a `Value` from plain data can't fail
+        let value = serde_json::to_value(value).unwrap_or(Value::Null);
+        self.extensions.insert(key.into(), value);
+        self
+    }
+
+    fn body(&self) -> Body {
+        Body::from(serde_json::to_vec(self).unwrap_or_default())
+    }
+}
+
+impl IntoResponse for Problem {
+    fn into_response(self) -> Response {
+        let mut response = (self.status, self.body()).into_response();
+
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/problem+json"),
+        );
+
+        // Lets middleware enrich the problem after the handler ran
+        response.extensions_mut().insert(self);
+
+        response
+    }
+}
+
+/// Adds the request path and request id to problem responses.
+///
+/// The request id is the one set by `tower_http`'s `SetRequestIdLayer`, which has to run before
+/// this middleware.
+pub async fn problem_context(req: Request, next: Next) -> Response {
+    let path = req.uri().path().to_string();
+    let request_id = req
+        .extensions()
+        .get::<RequestId>()
+        .and_then(|id| id.header_value().to_str().ok())
+        .map(str::to_string);
+
+    let response = next.run(req).await;
+
+    match response.extensions().get::<Problem>() {
+        Some(problem) => {
+            let mut problem = problem.clone();
+
+            if problem.instance.is_none() {
+                problem = problem.with_instance(path);
+            }
+
+            if let Some(request_id) = request_id {
+                problem = problem.with_extension("request_id", request_id);
+            }
+
+            let (mut parts, _) = response.into_parts();
+
+            let body = problem.body();
+            parts.extensions.insert(problem);
+
+            Response::from_parts(parts, body)
+        }
+        None => response,
+    }
+}
@@ -0,0 +1,444 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::User;
+
+/// Fields of a user chosen by the client, for both creating and updating.
+pub struct NewUser {
+    pub name: String,
+    pub email: String,
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    NotFound(u64),
+    // Emails are unique across users
+    EmailTaken(String),
+    Io(io::Error),
+}
+
+impl From<io::Error> for StoreError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+/// A page of users ordered by id.
+pub struct Page {
+    pub users: Vec<User>,
+    // Id of the last user on the page, `None` when there are no more users
+    pub next_cursor: Option<u64>,
+}
+
+pub trait UserStore: Send + Sync {
+    fn create(&self, user: NewUser) -> Result<User, StoreError>;
+    fn get(&self, id: u64) -> Result<User, StoreError>;
+    /// Users with an id greater than `after`, at most `limit` of them.
+    fn list(&self, after: Option<u64>, limit: usize) -> Result<Page, StoreError>;
+    fn update(&self, id: u64, user: NewUser) -> Result<User, StoreError>;
+    fn delete(&self, id: u64) -> Result<(), StoreError>;
+}
+
+/// Contents of the file of a [`FileUserStore`].
+#[derive(Default, Serialize, Deserialize)]
+struct UsersFile {
+    // Kept along with the users, so the ids of deleted users aren't handed out again
+    next_id: u64,
+    users: Vec<User>,
+}
+
+#[derive(Default)]
+pub struct InMemoryUserStore {
+    next_id: AtomicU64,
+    users: Mutex<BTreeMap<u64, User>>,
+}
+
+impl InMemoryUserStore {
+    fn from_file(file: UsersFile) -> Self {
+        let users = file
+            .users
+            .into_iter()
+            .map(|user| (user.id, user))
+            .collect::<BTreeMap<_, _>>();
+
+        // Never below the users' ids, even if the file was edited by hand
+        let next_id = users
+            .last_key_value()
+            .map_or(file.next_id, |(id, _)| file.next_id.max(id + 1));
+
+        InMemoryUserStore {
+            next_id: AtomicU64::new(next_id),
+            users: Mutex::new(users),
+        }
+    }
+
+    // An independent copy, changing it leaves `self` as it is
+    fn fork(&self) -> Self {
+        InMemoryUserStore {
+            next_id: AtomicU64::new(self.next_id.load(Ordering::SeqCst)),
+            users: Mutex::new(self.users.lock().unwrap().clone()),
+        }
+    }
+
+    fn snapshot(&self) -> UsersFile {
+        UsersFile {
+            next_id: self.next_id.load(Ordering::SeqCst),
+            users: self.users.lock().unwrap().values().cloned().collect(),
+        }
+    }
+}
+
+fn check_email(
+    users: &BTreeMap<u64, User>,
+    email: &str,
+    id: Option<u64>,
+) -> Result<(), StoreError> {
+    let taken = users
+        .values()
+        .any(|user| user.email == email && Some(user.id) != id);
+
+    if taken {
+        Err(StoreError::EmailTaken(email.to_string()))
+    } else {
+        Ok(())
+    }
+}
+
+impl UserStore for InMemoryUserStore {
+    fn create(&self, user: NewUser) -> Result<User, StoreError> {
+        let mut users = self.users.lock().unwrap();
+
+        check_email(&users, &user.email, None)?;
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+
+        let user = User {
+            id,
+            name: user.name,
+            email: user.email,
+            created_at: Utc::now(),
+        };
+
+        users.insert(id, user.clone());
+
+        Ok(user)
+    }
+
+    fn get(&self, id: u64) -> Result<User, StoreError> {
+        self.users
+            .lock()
+            .unwrap()
+            .get(&id)
+            .cloned()
+            .ok_or(StoreError::NotFound(id))
+    }
+
+    fn list(&self, after: Option<u64>, limit: usize) -> Result<Page, StoreError> {
+        let users = self.users.lock().unwrap();
+
+        let start = after.map_or(0, |after| after.saturating_add(1));
+        let page = users
+            .range(start..)
+            .take(limit)
+            .map(|(_, user)| user.clone())
+            .collect::<Vec<_>>();
+
+        let next_cursor = page
+            .last()
+            .filter(|last| users.range(last.id + 1..).next().is_some())
+            .map(|last| last.id);
+
+        Ok(Page {
+            users: page,
+            next_cursor,
+        })
+    }
+
+    fn update(&self, id: u64, update: NewUser) -> Result<User, StoreError> {
+        let mut users = self.users.lock().unwrap();
+
+        check_email(&users, &update.email, Some(id))?;
+
+        let user = users.get_mut(&id).ok_or(StoreError::NotFound(id))?;
+        user.name = update.name;
+        user.email = update.email;
+
+        Ok(user.clone())
+    }
+
+    fn delete(&self, id: u64) -> Result<(), StoreError> {
+        self.users
+            .lock()
+            .unwrap()
+            .remove(&id)
+            .map(|_| ())
+            .ok_or(StoreError::NotFound(id))
+    }
+}
+
+/// Keeps the users in memory and writes all of them to a JSON file after every change.
+pub struct FileUserStore {
+    path: PathBuf,
+    // Held for the whole of a change, so the file is written in the same order as the changes
+    users: Mutex<InMemoryUserStore>,
+}
+
+impl FileUserStore {
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+
+        let file = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice::<UsersFile>(&bytes)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => UsersFile::default(),
+            Err(err) => return Err(err),
+        };
+
+        Ok(FileUserStore {
+            path,
+            users: Mutex::new(InMemoryUserStore::from_file(file)),
+        })
+    }
+
+    // Makes the change on a copy of the users and only keeps it once the copy is written, so a
+    // failed write leaves the memory as it is on disk
+    fn change<T>(
+        &self,
+        change: impl FnOnce(&InMemoryUserStore) -> Result<T, StoreError>,
+    ) -> Result<T, StoreError> {
+        let mut users = self.users.lock().unwrap();
+
+        let changed = users.fork();
+        let result = change(&changed)?;
+
+        self.persist(&changed)?;
+        *users = changed;
+
+        Ok(result)
+    }
+
+    fn persist(&self, users: &InMemoryUserStore) -> Result<(), StoreError> {
+        let json = serde_json::to_vec_pretty(&users.snapshot()).map_err(io::Error::from)?;
+
+        // Write to a temporary file first, so a crash never leaves a half written file behind
+        let tmp = self.path.with_extension("json.tmp");
+        fs::write(&tmp, json)?;
+        fs::rename(&tmp, &self.path)?;
+
+        Ok(())
+    }
+}
+
+impl UserStore for FileUserStore {
+    fn create(&self, user: NewUser) -> Result<User, StoreError> {
+        self.change(|users| users.create(user))
+    }
+
+    fn get(&self, id: u64) -> Result<User, StoreError> {
+        self.users.lock().unwrap().get(id)
+    }
+
+    fn list(&self, after: Option<u64>, limit: usize) -> Result<Page, StoreError> {
+        self.users.lock().unwrap().list(after, limit)
+    }
+
+    fn update(&self, id: u64, user: NewUser) -> Result<User, StoreError> {
+        self.change(|users| users.update(id, user))
+    }
+
+    fn delete(&self, id: u64) -> Result<(), StoreError> {
+        self.change(|users| users.delete(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    fn new_user(name: &str) -> NewUser {
+        NewUser {
+            name: name.to_string(),
+            email: format!("{}@example.com", name.to_lowercase()),
+        }
+    }
+
+    fn ids(page: &Page) -> Vec<u64> {
+        page.users.iter().map(|user| user.id).collect()
+    }
+
+    // A fresh directory for the test's files
+    fn temp_dir(test: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("error-handling-{test}-{}", std::process::id()));
+
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    fn names(store: &impl UserStore) -> Vec<String> {
+        let page = store.list(None, usize::MAX).unwrap();
+        page.users.into_iter().map(|user| user.name).collect()
+    }
+
+    fn read_names(path: &Path) -> Vec<String> {
+        let file = serde_json::from_slice::<UsersFile>(&fs::read(path).unwrap()).unwrap();
+        file.users.into_iter().map(|user| user.name).collect()
+    }
+
+    #[test]
+    fn test_crud() {
+        let store = InMemoryUserStore::default();
+
+        let ferris = store.create(new_user("Ferris")).unwrap();
+        let corro = store.create(new_user("Corro")).unwrap();
+
+        assert_eq!((ferris.id, corro.id), (0, 1));
+        assert_eq!(store.get(ferris.id).unwrap().email, "ferris@example.com");
+
+        assert!(matches!(
+            store.create(new_user("Ferris")),
+            Err(StoreError::EmailTaken(email)) if email == "ferris@example.com"
+        ));
+
+        let updated = store
+            .update(
+                ferris.id,
+                NewUser {
+                    name: "Ferris the Crab".to_string(),
+                    email: "ferris@example.com".to_string(),
+                },
+            )
+            .unwrap();
+
+        assert_eq!(updated.name, "Ferris the Crab");
+        assert_eq!(updated.created_at, ferris.created_at);
+        assert_eq!(store.get(ferris.id).unwrap().name, "Ferris the Crab");
+
+        // Taking the email of another user
+        assert!(matches!(
+            store.update(corro.id, new_user("Ferris")),
+            Err(StoreError::EmailTaken(_))
+        ));
+        assert!(matches!(
+            store.update(42, new_user("Nobody")),
+            Err(StoreError::NotFound(42))
+        ));
+
+        store.delete(ferris.id).unwrap();
+
+        assert!(matches!(store.get(ferris.id), Err(StoreError::NotFound(0))));
+        assert!(matches!(
+            store.delete(ferris.id),
+            Err(StoreError::NotFound(0))
+        ));
+
+        // Ids aren't reused
+        assert_eq!(store.create(new_user("Ferris")).unwrap().id, 2);
+    }
+
+    #[test]
+    fn test_pagination() {
+        let store = InMemoryUserStore::default();
+
+        for name in ["A", "B", "C", "D", "E"] {
+            store.create(new_user(name)).unwrap();
+        }
+
+        store.delete(2).unwrap();
+
+        let page = store.list(None, 2).unwrap();
+        assert_eq!(ids(&page), [0, 1]);
+        assert_eq!(page.next_cursor, Some(1));
+
+        // The deleted user is skipped
+        let page = store.list(page.next_cursor, 2).unwrap();
+        assert_eq!(ids(&page), [3, 4]);
+        assert_eq!(page.next_cursor, None);
+
+        let page = store.list(Some(4), 2).unwrap();
+        assert!(page.users.is_empty());
+        assert_eq!(page.next_cursor, None);
+
+        let page = store.list(Some(u64::MAX), 2).unwrap();
+        assert!(page.users.is_empty());
+    }
+
+    #[test]
+    fn test_file_store() {
+        let path = temp_dir("file-store").join("users.json");
+
+        let store = FileUserStore::open(&path).unwrap();
+        assert!(names(&store).is_empty());
+
+        store.create(new_user("Ferris")).unwrap();
+        store.create(new_user("Corro")).unwrap();
+        store.update(1, new_user("Crab")).unwrap();
+
+        assert_eq!(read_names(&path), ["Ferris", "Crab"]);
+
+        store.delete(0).unwrap();
+
+        assert_eq!(read_names(&path), ["Crab"]);
+
+        // Loading the file restores the users and carries on with their ids
+        let store = FileUserStore::open(&path).unwrap();
+
+        assert_eq!(names(&store), ["Crab"]);
+        assert_eq!(store.create(new_user("Ferris")).unwrap().id, 2);
+
+        // The id of the last user isn't handed out again after a restart either
+        store.delete(2).unwrap();
+
+        let store = FileUserStore::open(&path).unwrap();
+
+        assert_eq!(names(&store), ["Crab"]);
+        assert_eq!(store.create(new_user("Corro")).unwrap().id, 3);
+    }
+
+    #[test]
+    fn test_file_store_write_failure() {
+        let dir = temp_dir("write-failure");
+        let path = dir.join("users.json");
+
+        let store = FileUserStore::open(&path).unwrap();
+        store.create(new_user("Ferris")).unwrap();
+
+        // Every write fails while the directory is gone
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(matches!(
+            store.create(new_user("Corro")),
+            Err(StoreError::Io(_))
+        ));
+        assert!(matches!(
+            store.update(0, new_user("Crab")),
+            Err(StoreError::Io(_))
+        ));
+        assert!(matches!(store.delete(0), Err(StoreError::Io(_))));
+
+        // None of the failed changes made it into memory
+        assert_eq!(names(&store), ["Ferris"]);
+
+        fs::create_dir_all(&dir).unwrap();
+
+        let corro = store.create(new_user("Corro")).unwrap();
+
+        assert_eq!(corro.id, 1);
+        assert_eq!(read_names(&path), ["Ferris", "Corro"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,132 @@
+use serde::Serialize;
+
+/// Types that can check their own fields after being deserialized.
+///
+/// Usually implemented with [`rules!`] rather than by hand.
+pub trait Validate {
+    fn validate(&self, v: &mut Validator);
+}
+
+/// Declares the checks of each field of a type, implementing [`Validate`] for it.
+///
+/// Rules are the methods of [`Field`], several rules of a field are separated by commas:
+///
+/// ```ignore
+/// validation::rules! {
+///     UserParams {
+///         name: length(1, 64);
+///         email: length(3, 254), email;
+///     }
+/// }
+/// ```
+macro_rules! rules {
+    ($ty:ty { $($field:ident: $($rule:ident $(($($arg:expr),*))?),+;)+ }) => {
+        impl $crate::validation::Validate for $ty {
+            fn validate(&self, v: &mut $crate::validation::Validator) {
+                $(
+                    v.field(stringify!($field), &self.$field)
+                        $(.$rule($($($arg),*)?))+;
+                )+
+            }
+        }
+    };
+}
+
+pub(crate) use rules;
+
+/// A field that failed validation, with the path of the field in the request body.
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        FieldError {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+}
+
+/// Collects the errors of every field instead of stopping at the first one.
+#[derive(Debug, Default)]
+pub struct Validator {
+    errors: Vec<FieldError>,
+}
+
+impl Validator {
+    pub fn field<'a>(&'a mut self, name: &'a str, value: &'a str) -> Field<'a> {
+        Field {
+            validator: self,
+            name,
+            value,
+        }
+    }
+
+    pub fn into_errors(self) -> Vec<FieldError> {
+        self.errors
+    }
+}
+
+/// Runs the checks of a [`Validate`] type, returning every failed field.
+pub fn validate<T: Validate>(value: &T) -> Result<(), Vec<FieldError>> {
+    let mut validator = Validator::default();
+    value.validate(&mut validator);
+
+    let errors = validator.into_errors();
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+pub struct Field<'a> {
+    validator: &'a mut Validator,
+    name: &'a str,
+    value: &'a str,
+}
+
+impl Field<'_> {
+    /// Length in characters, both bounds inclusive.
+    pub fn length(self, min: usize, max: usize) -> Self {
+        let length = self.value.chars().count();
+
+        if length < min || length > max {
+            self.error(format!("must be between {min} and {max} characters long"))
+        } else {
+            self
+        }
+    }
+
+    /// Loose `local@domain.tld` check, enough to catch typos but not a full RFC 5322 parser.
+    pub fn email(self) -> Self {
+        let valid = match self.value.split_once('@') {
+            Some((local, domain)) => {
+                !local.is_empty()
+                    && !domain.contains('@')
+                    && domain
+                        .split_once('.')
+                        .is_some_and(|(host, tld)| !host.is_empty() && !tld.is_empty())
+                    && !self.value.chars().any(char::is_whitespace)
+            }
+            None => false,
+        };
+
+        if valid {
+            self
+        } else {
+            self.error("must be a valid email address")
+        }
+    }
+
+    fn error(self, message: impl Into<String>) -> Self {
+        self.validator
+            .errors
+            .push(FieldError::new(self.name, message));
+        self
+    }
+}
@@ -0,0 +1,341 @@
+//! Which destinations clients may open tunnels to.
+
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use ipnet::{IpNet, Ipv4Net};
+
+/// A destination host, as `example.com` or `*.example.com`, or a network in CIDR notation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rule {
+    Host(String),
+    Net(IpNet),
+}
+
+impl Rule {
+    fn matches(&self, host: &str, ip: IpAddr) -> bool {
+        match self {
+            Rule::Host(pattern) => match pattern.strip_prefix("*.") {
+                // Only subdomains, `*.example.com` doesn't match `example.com` itself
+                Some(domain) => host
+                    .strip_suffix(domain)
+                    .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+                None => host == pattern,
+            },
+            // `::ffff:10.0.0.1` reaches the same host as `10.0.0.1`
+            Rule::Net(net) => net.contains(&ip.to_canonical()),
+        }
+    }
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(rule: &str) -> Result<Self, Self::Err> {
+        let rule = rule.trim();
+
+        if rule.contains('/') {
+            rule.parse()
+                .map(|net| Rule::Net(canonical_net(net)))
+                .map_err(|err| format!("invalid CIDR `{rule}`: {err}"))
+        } else if let Ok(ip) = rule.parse::<IpAddr>() {
+            Ok(Rule::Net(ip.to_canonical().into()))
+        } else if rule.is_empty() {
+            Err("empty rule".to_string())
+        } else {
+            Ok(Rule::Host(normalize_host(rule)))
+        }
+    }
+}
+
+// Rules are matched against canonical addresses, so IPv4-mapped networks have to be IPv4 as well
+fn canonical_net(net: IpNet) -> IpNet {
+    match net {
+        IpNet::V6(v6) if v6.prefix_len() >= 96 => match v6.addr().to_ipv4_mapped() {
+            Some(v4) => Ipv4Net::new(v4, v6.prefix_len() - 96)
+                .expect("prefix is at most 32")
+                .into(),
+            None => net,
+        },
+        _ => net,
+    }
+}
+
+// Names are case insensitive, and `example.com.` is the same fully qualified name as `example.com`
+fn normalize_host(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Why a tunnel was refused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Denied {
+    Port(u16),
+    Target(SocketAddr),
+}
+
+impl fmt::Display for Denied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Denied::Port(port) => write!(f, "port {port} is not allowed"),
+            Denied::Target(addr) => write!(f, "{addr} is not allowed"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TargetError {
+    Denied(Denied),
+    Lookup(std::io::Error),
+}
+
+impl fmt::Display for TargetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TargetError::Denied(denied) => denied.fmt(f),
+            TargetError::Lookup(err) => write!(f, "lookup failed: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for TargetError {}
+
+/// Allow and deny lists for tunnel destinations.
+///
+/// Deny rules win over allow rules. An empty allow list allows everything that isn't denied,
+/// and an empty port list allows every port.
+#[derive(Debug, Clone, Default)]
+pub struct AccessControl {
+    pub allow: Vec<Rule>,
+    pub deny: Vec<Rule>,
+    pub ports: Vec<u16>,
+}
+
+impl AccessControl {
+    fn check_port(&self, port: u16) -> Result<(), Denied> {
+        if !self.ports.is_empty() && !self.ports.contains(&port) {
+            return Err(Denied::Port(port));
+        }
+
+        Ok(())
+    }
+
+    /// Whether a tunnel to `host`, resolved to `addr`, is allowed, whatever the port.
+    fn check_target(&self, host: &str, addr: SocketAddr) -> Result<(), Denied> {
+        let host = normalize_host(host);
+        let matches = |rule: &Rule| rule.matches(&host, addr.ip());
+
+        if self.deny.iter().any(matches)
+            || !(self.allow.is_empty() || self.allow.iter().any(matches))
+        {
+            return Err(Denied::Target(addr));
+        }
+
+        Ok(())
+    }
+
+    /// Resolves `host` and returns the addresses a tunnel may connect to.
+    ///
+    /// Every address the name resolves to has to be allowed, so a name can't be used to reach a
+    /// denied network. Connecting to exactly these addresses also means a second lookup can't
+    /// return different ones.
+    pub async fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, TargetError> {
+        self.check_port(port).map_err(TargetError::Denied)?;
+        self.lookup(host, port).await
+    }
+
+    /// Like [`AccessControl::resolve`], but leaves checking the port to the caller.
+    ///
+    /// For connectors that resolve names by themselves and only know the port afterwards.
+    pub async fn lookup(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, TargetError> {
+        // IPv6 addresses come in brackets in authorities
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+
+        let addrs: Vec<_> = tokio::net::lookup_host((host, port))
+            .await
+            .map_err(TargetError::Lookup)?
+            .collect();
+
+        for addr in &addrs {
+            self.check_target(host, *addr)
+                .map_err(TargetError::Denied)?;
+        }
+
+        Ok(addrs)
+    }
+}
+
+/// Parses a comma separated list, as used by the `PROXY_*` environment variables.
+pub fn parse_list<T: FromStr>(list: &str) -> Result<Vec<T>, T::Err> {
+    list.split(',')
+        .filter(|item| !item.trim().is_empty())
+        .map(|item| item.trim().parse())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    // Everything `resolve` checks, for an address it looked up
+    fn check(access: &AccessControl, host: &str, addr: SocketAddr) -> Result<(), Denied> {
+        access.check_port(addr.port())?;
+        access.check_target(host, addr)
+    }
+
+    #[test]
+    fn test_rules() {
+        let access = AccessControl {
+            allow: parse_list("example.com, *.internal.example.com, 10.0.0.0/8").unwrap(),
+            deny: parse_list("10.0.0.1").unwrap(),
+            ports: vec![443],
+        };
+
+        assert_eq!(
+            check(&access, "example.com", addr("93.184.215.14:443")),
+            Ok(())
+        );
+        assert_eq!(
+            check(&access, "EXAMPLE.com", addr("93.184.215.14:443")),
+            Ok(())
+        );
+        assert_eq!(
+            check(&access, "api.internal.example.com", addr("192.0.2.1:443")),
+            Ok(())
+        );
+        assert_eq!(check(&access, "10.1.2.3", addr("10.1.2.3:443")), Ok(()));
+        assert_eq!(
+            check(&access, "example.com.", addr("93.184.215.14:443")),
+            Ok(())
+        );
+
+        assert_eq!(
+            check(&access, "example.com", addr("93.184.215.14:22")),
+            Err(Denied::Port(22))
+        );
+        assert_eq!(
+            check(&access, "internal.example.com", addr("192.0.2.1:443")),
+            Err(Denied::Target(addr("192.0.2.1:443")))
+        );
+        assert_eq!(
+            check(&access, "evilexample.com", addr("192.0.2.1:443")),
+            Err(Denied::Target(addr("192.0.2.1:443")))
+        );
+        assert_eq!(
+            check(&access, "example.com", addr("10.0.0.1:443")),
+            Err(Denied::Target(addr("10.0.0.1:443")))
+        );
+    }
+
+    #[test]
+    fn test_fully_qualified_names() {
+        let access = AccessControl {
+            deny: parse_list("evil.example.com, *.internal.example.com, Dotted.example.com.")
+                .unwrap(),
+            ..Default::default()
+        };
+        let target = addr("192.0.2.1:443");
+
+        for host in [
+            "evil.example.com.",
+            "EVIL.example.com.",
+            "evil.example.com..",
+            "api.internal.example.com.",
+            "API.Internal.Example.Com",
+            "dotted.example.com",
+            "dotted.example.com.",
+        ] {
+            assert_eq!(
+                check(&access, host, target),
+                Err(Denied::Target(target)),
+                "{host}"
+            );
+        }
+
+        assert_eq!(check(&access, "example.com.", target), Ok(()));
+        assert_eq!(check(&access, "internal.example.com.", target), Ok(()));
+    }
+
+    #[test]
+    fn test_ipv4_mapped_addresses() {
+        let access = AccessControl {
+            deny: parse_list("127.0.0.0/8, 10.0.0.0/8, ::ffff:192.168.0.0/112").unwrap(),
+            ..Default::default()
+        };
+
+        for target in [
+            "[::ffff:127.0.0.1]:22",
+            "[::ffff:10.0.0.1]:443",
+            "[::ffff:7f00:1]:22",
+            "192.168.1.1:80",
+            "[::ffff:192.168.1.1]:80",
+        ] {
+            let target = addr(target);
+            let host = target.ip().to_string();
+
+            assert_eq!(
+                check(&access, &host, target),
+                Err(Denied::Target(target)),
+                "{target}"
+            );
+        }
+
+        assert_eq!(check(&access, "::1", addr("[::1]:22")), Ok(()));
+        assert_eq!(check(&access, "192.0.2.1", addr("192.0.2.1:22")), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_ipv4_mapped_addresses() {
+        let access = AccessControl {
+            deny: parse_list("127.0.0.0/8").unwrap(),
+            ..Default::default()
+        };
+
+        // `CONNECT [::ffff:127.0.0.1]:22`
+        assert!(matches!(
+            access.resolve("[::ffff:127.0.0.1]", 22).await,
+            Err(TargetError::Denied(Denied::Target(_)))
+        ));
+
+        // Forwarded requests and SOCKS5 targets
+        assert!(matches!(
+            access.lookup("::ffff:127.0.0.1", 0).await,
+            Err(TargetError::Denied(Denied::Target(_)))
+        ));
+    }
+
+    #[test]
+    fn test_parse_rules() {
+        assert_eq!(
+            parse_list::<Rule>("Example.com,,::1, 192.168.0.0/16, ::ffff:10.0.0.1, ::ffff:0:0/96")
+                .unwrap(),
+            vec![
+                Rule::Host("example.com".to_string()),
+                Rule::Net("::1/128".parse().unwrap()),
+                Rule::Net("192.168.0.0/16".parse().unwrap()),
+                Rule::Net("10.0.0.1/32".parse().unwrap()),
+                Rule::Net("0.0.0.0/0".parse().unwrap()),
+            ]
+        );
+        assert!("10.0.0.0/33".parse::<Rule>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resolve_denied_network() {
+        let access = AccessControl {
+            deny: parse_list("127.0.0.0/8, ::1").unwrap(),
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            access.resolve("localhost", 80).await,
+            Err(TargetError::Denied(Denied::Target(_)))
+        ));
+    }
+}
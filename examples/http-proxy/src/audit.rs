@@ -0,0 +1,81 @@
+//! One log entry per tunnel or forwarded request, under the `audit` target.
+//!
+//! Run with `RUST_LOG=audit=info` to only see these.
+
+use std::{fmt, net::SocketAddr, time::Instant};
+
+use hyper::StatusCode;
+
+/// Who asked for a connection to where, logged once it is refused, fails or ends.
+pub struct Audit {
+    client: SocketAddr,
+    user: Option<String>,
+    target: String,
+    started: Instant,
+}
+
+impl Audit {
+    pub fn new(client: SocketAddr, target: impl Into<String>) -> Self {
+        Audit {
+            client,
+            user: None,
+            target: target.into(),
+            started: Instant::now(),
+        }
+    }
+
+    pub fn user(mut self, user: Option<&str>) -> Self {
+        self.user = user.map(str::to_string);
+        self
+    }
+
+    pub fn denied(self, reason: impl fmt::Display) {
+        tracing::info!(
+            target: "audit",
+            client = %self.client,
+            user = self.user.as_deref(),
+            destination = %self.target,
+            %reason,
+            "denied"
+        );
+    }
+
+    pub fn failed(self, err: impl fmt::Display) {
+        tracing::info!(
+            target: "audit",
+            client = %self.client,
+            user = self.user.as_deref(),
+            destination = %self.target,
+            error = %err,
+            elapsed = ?self.started.elapsed(),
+            "failed"
+        );
+    }
+
+    /// `sent` went from the client to the target, `received` the other way.
+    pub fn closed(self, sent: u64, received: u64, reason: impl fmt::Display) {
+        tracing::info!(
+            target: "audit",
+            client = %self.client,
+            user = self.user.as_deref(),
+            destination = %self.target,
+            sent,
+            received,
+            %reason,
+            elapsed = ?self.started.elapsed(),
+            "tunnel closed"
+        );
+    }
+
+    pub fn forwarded(self, status: StatusCode) {
+        tracing::info!(
+            target: "audit",
+            client = %self.client,
+            user = self.user.as_deref(),
+            destination = %self.target,
+            status = status.as_u16(),
+            elapsed = ?self.started.elapsed(),
+            "request forwarded"
+        );
+    }
+}
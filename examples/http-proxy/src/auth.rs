@@ -0,0 +1,82 @@
+//! `Proxy-Authorization` with the Basic scheme.
+
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl Credentials {
+    /// Returns the username if the request carries these credentials.
+    pub fn verify(&self, headers: &HeaderMap) -> Option<&str> {
+        let value = headers.get(header::PROXY_AUTHORIZATION)?.to_str().ok()?;
+        let (scheme, encoded) = value.split_once(' ')?;
+
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+
+        let decoded = STANDARD.decode(encoded.trim()).ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let (username, password) = decoded.split_once(':')?;
+
+        self.matches(username, password)
+            .then_some(self.username.as_str())
+    }
+
+    pub fn matches(&self, username: &str, password: &str) -> bool {
+        // Both are compared in full, so the response time doesn't tell which one was wrong
+        constant_time_eq(username.as_bytes(), self.username.as_bytes())
+            & constant_time_eq(password.as_bytes(), self.password.as_bytes())
+    }
+}
+
+/// The 407 response asking the client to authenticate.
+pub fn challenge() -> Response {
+    (
+        StatusCode::PROXY_AUTHENTICATION_REQUIRED,
+        [(header::PROXY_AUTHENTICATE, r#"Basic realm="proxy""#)],
+        Body::empty(),
+    )
+        .into_response()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        let credentials = Credentials {
+            username: "alice".to_string(),
+            password: "s3cret:with:colons".to_string(),
+        };
+
+        let headers = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::PROXY_AUTHORIZATION, value.parse().unwrap());
+            headers
+        };
+        let basic = |user_pass: &str| headers(&format!("Basic {}", STANDARD.encode(user_pass)));
+
+        assert_eq!(
+            credentials.verify(&basic("alice:s3cret:with:colons")),
+            Some("alice")
+        );
+        assert_eq!(credentials.verify(&basic("alice:wrong")), None);
+        assert_eq!(credentials.verify(&basic("bob:s3cret:with:colons")), None);
+        assert_eq!(credentials.verify(&headers("Bearer abc")), None);
+        assert_eq!(credentials.verify(&HeaderMap::new()), None);
+    }
+}
@@ -0,0 +1,143 @@
+//! Forwarding of plain HTTP requests in absolute-form, like `GET http://example.com/ HTTP/1.1`.
+
+use std::{
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, HeaderName, HeaderValue, Version},
+    response::{IntoResponse, Response},
+};
+use hyper::{Request, StatusCode};
+use hyper_util::{
+    client::legacy::{
+        connect::{dns::Name, HttpConnector},
+        Client,
+    },
+    rt::TokioExecutor,
+};
+use tower::Service;
+
+use crate::access::{AccessControl, TargetError};
+
+pub type HttpClient = Client<HttpConnector<Resolver>, Body>;
+
+// Name of the proxy in `Via` headers
+const PSEUDONYM: &str = "http-proxy";
+
+/// Keeps connections to upstreams open and reuses them across requests.
+///
+/// Only connects to addresses `access` allows, see [`Resolver`].
+pub fn client(connect_timeout: Duration, access: AccessControl) -> HttpClient {
+    let mut connector = HttpConnector::new_with_resolver(Resolver {
+        access: Arc::new(access),
+    });
+    connector.set_connect_timeout(Some(connect_timeout));
+
+    Client::builder(TokioExecutor::new()).build(connector)
+}
+
+/// Looks up upstream names for the client with [`AccessControl::lookup`].
+///
+/// The client resolves the name again after the request was admitted, and by then it may
+/// resolve to a denied address. IP addresses skip the lookup, but they can't change either.
+#[derive(Clone)]
+pub struct Resolver {
+    access: Arc<AccessControl>,
+}
+
+impl Service<Name> for Resolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = TargetError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let access = self.access.clone();
+
+        // The connector puts in the port of the request, which was checked when admitting it
+        Box::pin(async move { Ok(access.lookup(name.as_str(), 0).await?.into_iter()) })
+    }
+}
+
+/// Sends `req` on to the server in its URI and streams the response back.
+pub async fn forward(client: HttpClient, mut req: Request<Body>) -> Response {
+    if req.uri().scheme_str() != Some("http") {
+        return (
+            StatusCode::BAD_REQUEST,
+            "only http:// requests can be forwarded, use CONNECT for anything else",
+        )
+            .into_response();
+    }
+
+    let via = via(req.version());
+    remove_hop_by_hop_headers(req.headers_mut());
+    req.headers_mut().append(header::VIA, via.clone());
+
+    // Let the client pick the version to talk to the upstream
+    *req.version_mut() = Version::default();
+
+    match client.request(req).await {
+        Ok(res) => {
+            let mut res = res.map(Body::new);
+            remove_hop_by_hop_headers(res.headers_mut());
+            res.headers_mut().append(header::VIA, via);
+            res
+        }
+        Err(err) => {
+            tracing::warn!("forwarding failed: {:?}", err);
+            (StatusCode::BAD_GATEWAY, "upstream request failed").into_response()
+        }
+    }
+}
+
+fn via(version: Version) -> HeaderValue {
+    let protocol = match version {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_2 => "2",
+        Version::HTTP_3 => "3",
+        _ => "1.1",
+    };
+
+    HeaderValue::from_str(&format!("{protocol} {PSEUDONYM}")).unwrap()
+}
+
+/// Removes the headers that only apply to a single connection, see RFC 9110 section 7.6.1.
+fn remove_hop_by_hop_headers(headers: &mut HeaderMap) {
+    // Headers named in `Connection` are hop-by-hop as well
+    let listed: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| name.trim().parse().ok())
+        .collect();
+
+    for name in listed {
+        headers.remove(name);
+    }
+
+    for name in [
+        header::CONNECTION,
+        HeaderName::from_static("keep-alive"),
+        HeaderName::from_static("proxy-connection"),
+        header::PROXY_AUTHENTICATE,
+        header::PROXY_AUTHORIZATION,
+        header::TE,
+        header::TRAILER,
+        header::TRANSFER_ENCODING,
+        header::UPGRADE,
+    ] {
+        headers.remove(name);
+    }
+}
@@ -1,112 +1,1083 @@
-use std::net::SocketAddr;
+mod access;
+mod audit;
+mod auth;
+mod forward;
+mod mitm;
+mod socks;
+mod tunnel;
 
+use std::{
+    fmt::Display, future::Future, io, net::SocketAddr, path::Path, str::FromStr, sync::Arc,
+    time::Duration,
+};
+
+use access::{AccessControl, TargetError};
+use audit::Audit;
+use auth::Credentials;
 use axum::{
     body::Body,
     response::{IntoResponse, Response},
     routing::get,
     Router,
 };
-use hyper::{body::Incoming, server::conn::http1, upgrade::Upgraded, Method, Request, StatusCode};
-use hyper_util::rt::TokioIo;
-use tokio::net::{TcpListener, TcpStream};
+use forward::HttpClient;
+use hyper::{body::Incoming, HeaderMap, Method, Request, StatusCode, Version};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+};
+use mitm::Mitm;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{watch, OwnedSemaphorePermit, Semaphore},
+};
 use tower::{Service, ServiceExt};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+struct Config {
+    /// Clients have to authenticate with these when set.
+    credentials: Option<Credentials>,
+    access: AccessControl,
+    /// How long connecting to a target may take.
+    connect_timeout: Duration,
+    /// Tunnels without traffic in either direction for this long are closed.
+    idle_timeout: Duration,
+    /// Tunnels open at the same time, clients asking for more get a 503.
+    max_tunnels: u32,
+    /// Client connections served at the same time, more wait to be accepted.
+    max_connections: u32,
+    /// How long open connections and tunnels get to finish on shutdown.
+    drain_timeout: Duration,
+    /// Decrypts and logs the traffic of tunnels when set, for debugging.
+    mitm: Option<Arc<Mitm>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            credentials: None,
+            access: AccessControl::default(),
+            connect_timeout: Duration::from_secs(10),
+            idle_timeout: Duration::from_secs(300),
+            max_tunnels: 1024,
+            max_connections: 4096,
+            drain_timeout: Duration::from_secs(30),
+            mitm: None,
+        }
+    }
+}
+
+/// What the connections of a running proxy share.
+struct ProxyState {
+    config: Config,
+    http_client: HttpClient,
+    // One permit per open tunnel
+    tunnels: Arc<Semaphore>,
+}
+
 #[tokio::main]
 async fn main() {
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
-                format!("{}=trace,tower_http=debug", env!("CARGO_CRATE_NAME")).into()
+                format!(
+                    "{}=trace,tower_http=debug,audit=info",
+                    env!("CARGO_CRATE_NAME")
+                )
+                .into()
             }),
         )
+        .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let router_svc = Router::new().route("/", get(|| async { "Hello, World !" }));
+    let config = config_from_env();
 
-    let tower_service = tower::service_fn(move |req: Request<_>| {
-        let router_svc = router_svc.clone();
-        let req = req.map(Body::new);
-        async move {
-            // Handle CONNECT requests for tunneling
-            if req.method() == Method::CONNECT {
-                proxy(req).await.map_err(|err| err.to_string())
-            } else {
-                // Handle other requests by routing them to the router
-                router_svc.oneshot(req).await.map_err(|err| err.to_string())
-            }
-        }
+    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
+
+    let listener = TcpListener::bind(addr).await.unwrap();
+
+    // SOCKS5 is only served when given an address to listen on
+    let socks = match std::env::var("PROXY_SOCKS_ADDR") {
+        Ok(addr) => Some(TcpListener::bind(addr).await.unwrap()),
+        Err(_) => None,
+    };
+
+    serve(listener, socks, config, shutdown_signal()).await;
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install signal handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+// Everything can be set with environment variables, lists are comma separated and durations in
+// seconds
+fn config_from_env() -> Config {
+    fn list<T>(name: &str) -> Vec<T>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        std::env::var(name)
+            .map(|list| {
+                access::parse_list(&list).unwrap_or_else(|err| panic!("invalid {name}: {err}"))
+            })
+            .unwrap_or_default()
+    }
+
+    // A value that doesn't parse, e.g. a limit too big for its type, stops the proxy instead of
+    // quietly running with the default
+    fn parsed<T>(name: &str, default: T) -> T
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        std::env::var(name)
+            .map(|value| {
+                value
+                    .parse()
+                    .unwrap_or_else(|err| panic!("invalid {name}: {err}"))
+            })
+            .unwrap_or(default)
+    }
+
+    fn secs(name: &str, default: Duration) -> Duration {
+        std::env::var(name)
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .map(Duration::from_secs_f64)
+            .unwrap_or(default)
+    }
+
+    // Interception is only turned on by saying where to keep its CA
+    let mitm = std::env::var_os("PROXY_MITM_DIR").map(|dir| {
+        let extra_roots = std::env::var_os("PROXY_MITM_UPSTREAM_CA");
+        let roots = mitm::upstream_roots(extra_roots.as_ref().map(Path::new))
+            .expect("failed to load PROXY_MITM_UPSTREAM_CA");
+
+        tracing::warn!("TLS interception is on, tunneled traffic is decrypted and logged");
+
+        Arc::new(Mitm::load_or_create(Path::new(&dir), roots).expect("failed to load the CA"))
     });
 
-    let hyper_service =
-        hyper::service::service_fn(move |req: Request<Incoming>| tower_service.clone().call(req));
+    let default = Config::default();
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
+    let credentials = credentials(
+        std::env::var("PROXY_USERNAME").ok(),
+        std::env::var("PROXY_PASSWORD").ok(),
+    );
 
-    let listener = TcpListener::bind(addr).await.unwrap();
+    Config {
+        credentials,
+        access: AccessControl {
+            allow: list("PROXY_ALLOW"),
+            deny: list("PROXY_DENY"),
+            ports: list("PROXY_PORTS"),
+        },
+        connect_timeout: secs("PROXY_CONNECT_TIMEOUT", default.connect_timeout),
+        idle_timeout: secs("PROXY_IDLE_TIMEOUT", default.idle_timeout),
+        max_tunnels: parsed("PROXY_MAX_TUNNELS", default.max_tunnels),
+        max_connections: parsed("PROXY_MAX_CONNECTIONS", default.max_connections),
+        drain_timeout: secs("PROXY_DRAIN_TIMEOUT", default.drain_timeout),
+        mitm,
+    }
+}
+
+fn credentials(username: Option<String>, password: Option<String>) -> Option<Credentials> {
+    match (username, password) {
+        (Some(username), Some(password)) => Some(Credentials { username, password }),
+        (None, None) => {
+            tracing::warn!(
+                "PROXY_USERNAME and PROXY_PASSWORD aren't set, anyone can use the proxy"
+            );
+            None
+        }
+        // Most likely a typo, running without authentication instead would go unnoticed
+        _ => panic!("PROXY_USERNAME and PROXY_PASSWORD have to be set together"),
+    }
+}
+
+/// Serves proxy clients, and SOCKS5 clients on `socks` if set, until `shutdown` completes, then
+/// waits for open connections and tunnels to finish for at most `drain_timeout`.
+async fn serve(
+    listener: TcpListener,
+    socks: Option<TcpListener>,
+    config: Config,
+    shutdown: impl Future<Output = ()>,
+) {
+    let router_svc = Router::new().route("/", get(|| async { "Hello, World !" }));
+
+    let max_connections = config.max_connections;
+    let connections = Arc::new(Semaphore::new(max_connections as usize));
+    let state = Arc::new(ProxyState {
+        http_client: forward::client(config.connect_timeout, config.access.clone()),
+        tunnels: Arc::new(Semaphore::new(config.max_tunnels as usize)),
+        config,
+    });
+
+    // Dropped to tell connections to finish their current request and close
+    let (stop_tx, stop_rx) = watch::channel(());
+
+    tokio::pin!(shutdown);
 
     loop {
-        let (stream, _) = listener.accept().await.unwrap();
+        let (permit, (stream, client)) = tokio::select! {
+            _ = &mut shutdown => break,
+            accepted = accept(&listener, &connections) => accepted,
+            (permit, (stream, client)) = async {
+                match &socks {
+                    Some(socks) => accept(socks, &connections).await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                let state = state.clone();
+                tokio::task::spawn(async move {
+                    socks::handle(stream, client, state).await;
+                    drop(permit);
+                });
+                continue;
+            }
+        };
         let io = TokioIo::new(stream);
-        let hyper_service = hyper_service.clone();
+
+        let state = state.clone();
+        let router_svc = router_svc.clone();
+        let mut stop_rx = stop_rx.clone();
+
+        let tower_service = tower::service_fn(move |req: Request<_>| {
+            let state = state.clone();
+            let router_svc = router_svc.clone();
+            let req = req.map(Body::new);
+            async move {
+                // Handle CONNECT requests for tunneling
+                if req.method() == Method::CONNECT {
+                    proxy(req, client, state)
+                        .await
+                        .map_err(|err| err.to_string())
+                } else if req.version() < Version::HTTP_2 && req.uri().authority().is_some() {
+                    // Requests in absolute-form are meant for another server. Every HTTP/2
+                    // request has an authority, so only CONNECT is proxied over it.
+                    forward_request(req, client, state)
+                        .await
+                        .map_err(|err| err.to_string())
+                } else {
+                    // Handle other requests by routing them to the router
+                    router_svc.oneshot(req).await.map_err(|err| err.to_string())
+                }
+            }
+        });
+
+        let hyper_service = hyper::service::service_fn(move |req: Request<Incoming>| {
+            tower_service.clone().call(req)
+        });
+
         tokio::task::spawn(async move {
-            if let Err(err) = http1::Builder::new()
-                .preserve_header_case(true)
-                .serve_connection(io, hyper_service)
-                .with_upgrades()
-                .await
-            {
+            let mut builder = auto::Builder::new(TokioExecutor::new());
+            builder.http1().preserve_header_case(true);
+            // Extended CONNECT (RFC 8441), for clients tunneling another protocol
+            builder.http2().enable_connect_protocol();
+
+            let conn = builder.serve_connection_with_upgrades(io, hyper_service);
+            tokio::pin!(conn);
+
+            let res = tokio::select! {
+                res = conn.as_mut() => res,
+                _ = stop_rx.changed() => {
+                    conn.as_mut().graceful_shutdown();
+                    conn.await
+                }
+            };
+
+            if let Err(err) = res {
                 tracing::error!("Error serving connection: {:?}", err);
-                println!("Error serving connection: {:?}", err);
             }
+
+            drop(permit);
         });
     }
+
+    drop(listener);
+    drop(socks);
+    drop(stop_tx);
+
+    tracing::info!("Shutting down, waiting for connections and tunnels to finish");
+
+    // Every permit being back means every connection and tunnel is done
+    let drain = async {
+        let _ = connections.acquire_many(max_connections).await;
+        let _ = state.tunnels.acquire_many(state.config.max_tunnels).await;
+    };
+
+    if tokio::time::timeout(state.config.drain_timeout, drain)
+        .await
+        .is_err()
+    {
+        tracing::warn!("Drain timeout elapsed, closing the remaining connections");
+    }
 }
 
-async fn proxy(req: Request<Body>) -> Result<Response, hyper::Error> {
-    tracing::trace!(?req);
+/// Waits for a connection slot and the next client.
+async fn accept(
+    listener: &TcpListener,
+    connections: &Arc<Semaphore>,
+) -> (OwnedSemaphorePermit, (TcpStream, SocketAddr)) {
+    let permit = connections.clone().acquire_owned().await.unwrap();
 
-    if let Some(host_addr) = req.uri().authority().map(|auth| auth.to_string()) {
-        tokio::task::spawn(async move {
-            // Upgrade the connection and create a raw TCP connection
-            match hyper::upgrade::on(req).await {
-                Ok(upgraded) => {
-                    // Handle the upgraded connection
-                    if let Err(e) = tunnel(upgraded, host_addr).await {
-                        tracing::warn!("server io error {:?}", e);
-                    }
-                }
-                Err(err) => {
-                    tracing::warn!("upgrade error {:?}", err);
-                }
+    loop {
+        match listener.accept().await {
+            Ok(accepted) => return (permit, accepted),
+            // The client went away before we got to it, that's only its problem
+            Err(err) if is_connection_error(&err) => continue,
+            Err(err) => {
+                // Most likely out of file descriptors, which frees up as connections close
+                tracing::error!("Error accepting connection: {}", err);
+                tokio::time::sleep(Duration::from_millis(100)).await;
             }
-        });
+        }
+    }
+}
 
-        Ok(Response::new(Body::empty()))
-    } else {
-        tracing::warn!("CONNECT host is not socker addr: {:?}", req.uri());
+fn is_connection_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+    )
+}
 
-        Ok((
+async fn proxy(
+    req: Request<Body>,
+    client: SocketAddr,
+    state: Arc<ProxyState>,
+) -> Result<Response, hyper::Error> {
+    tracing::trace!(?req);
+
+    // Extended CONNECT has a scheme, which may imply the port
+    let default_port = match req.uri().scheme_str() {
+        Some("https") => Some(443),
+        Some("http") => Some(80),
+        _ => None,
+    };
+
+    let Some((host, port)) = req
+        .uri()
+        .authority()
+        .and_then(|auth| Some((auth.host().to_string(), auth.port_u16().or(default_port)?)))
+    else {
+        tracing::warn!("CONNECT host is not socket addr: {:?}", req.uri());
+
+        return Ok((
             StatusCode::BAD_REQUEST,
             "CONNECT must be to a socket address",
         )
-            .into_response())
+            .into_response());
+    };
+
+    let audit = Audit::new(client, format!("{host}:{port}"));
+
+    let (audit, addrs) = match admit(req.headers(), &host, port, audit, &state.config).await {
+        Ok(admitted) => admitted,
+        Err(res) => return Ok(res),
+    };
+
+    // Connect before answering, so the client learns about an unreachable target from the status
+    let (permit, server) = match open_target(&state, &addrs).await {
+        Ok(opened) => opened,
+        Err(err) => {
+            let status = match err {
+                OpenError::TooManyTunnels => StatusCode::SERVICE_UNAVAILABLE,
+                OpenError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+                OpenError::Connect(_) => StatusCode::BAD_GATEWAY,
+            };
+            let message = err.to_string();
+            err.audit(audit);
+
+            return Ok((status, message).into_response());
+        }
+    };
+
+    let idle_timeout = state.config.idle_timeout;
+
+    tokio::task::spawn(async move {
+        // Upgrade the connection and tunnel it to the target
+        match hyper::upgrade::on(req).await {
+            Ok(upgraded) => match &state.config.mitm {
+                Some(mitm) => {
+                    let host = host.trim_start_matches('[').trim_end_matches(']');
+                    mitm.intercept(TokioIo::new(upgraded), server, host, idle_timeout, audit)
+                        .await
+                }
+                None => tunnel::tunnel(TokioIo::new(upgraded), server, idle_timeout, audit).await,
+            },
+            Err(err) => {
+                tracing::warn!("upgrade error {:?}", err);
+                audit.failed(err);
+            }
+        }
+
+        drop(permit);
+    });
+
+    Ok(Response::new(Body::empty()))
+}
+
+/// Why a tunnel couldn't be opened.
+#[derive(Debug)]
+enum OpenError {
+    TooManyTunnels,
+    Timeout,
+    Connect(io::Error),
+}
+
+impl Display for OpenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OpenError::TooManyTunnels => write!(f, "too many tunnels"),
+            OpenError::Timeout => write!(f, "connect timeout"),
+            OpenError::Connect(err) => write!(f, "connecting failed: {err}"),
+        }
+    }
+}
+
+impl OpenError {
+    fn audit(self, audit: Audit) {
+        match self {
+            OpenError::TooManyTunnels => audit.denied(self),
+            _ => audit.failed(self),
+        }
     }
 }
 
-async fn tunnel(upgraded: Upgraded, addr: String) -> std::io::Result<()> {
-    let mut server = TcpStream::connect(addr).await?;
-    let mut upgraded = TokioIo::new(upgraded);
+/// Takes a tunnel slot, held until the permit is dropped, and connects to the first of `addrs`
+/// that answers.
+async fn open_target(
+    state: &ProxyState,
+    addrs: &[SocketAddr],
+) -> Result<(OwnedSemaphorePermit, TcpStream), OpenError> {
+    let permit = state
+        .tunnels
+        .clone()
+        .try_acquire_owned()
+        .map_err(|_| OpenError::TooManyTunnels)?;
 
-    // Write/read data bidirectionally between the client and the server
-    let (from_client, from_server) =
-        tokio::io::copy_bidirectional(&mut upgraded, &mut server).await?;
+    let server = tokio::time::timeout(state.config.connect_timeout, TcpStream::connect(addrs))
+        .await
+        .map_err(|_| OpenError::Timeout)?
+        .map_err(OpenError::Connect)?;
 
-    tracing::debug!(
-        "client wrote {} bytes and received {} bytes",
-        from_client,
-        from_server
-    );
+    Ok((permit, server))
+}
+
+async fn forward_request(
+    req: Request<Body>,
+    client: SocketAddr,
+    state: Arc<ProxyState>,
+) -> Result<Response, hyper::Error> {
+    tracing::trace!(?req);
+
+    let host = req.uri().host().unwrap_or_default().to_string();
+    let port = req.uri().port_u16().unwrap_or(80);
+
+    let audit = Audit::new(client, format!("{} {}", req.method(), req.uri()));
+
+    // Pooled connections are keyed by host, so they can't be handed the checked addresses. The
+    // client checks the addresses again when it looks the host up itself.
+    let (audit, _) = match admit(req.headers(), &host, port, audit, &state.config).await {
+        Ok(admitted) => admitted,
+        Err(res) => return Ok(res),
+    };
+
+    let res = forward::forward(state.http_client.clone(), req).await;
+    audit.forwarded(res.status());
+
+    Ok(res)
+}
+
+/// Checks the client's credentials and whether the target is allowed, returning the addresses
+/// to connect to or the response refusing the request.
+async fn admit(
+    headers: &HeaderMap,
+    host: &str,
+    port: u16,
+    audit: Audit,
+    config: &Config,
+) -> Result<(Audit, Vec<SocketAddr>), Response> {
+    let audit = match &config.credentials {
+        Some(credentials) => match credentials.verify(headers) {
+            Some(user) => audit.user(Some(user)),
+            None => {
+                audit.denied("missing or invalid credentials");
+                return Err(auth::challenge());
+            }
+        },
+        None => audit,
+    };
+
+    match config.access.resolve(host, port).await {
+        Ok(addrs) => Ok((audit, addrs)),
+        Err(TargetError::Denied(denied)) => {
+            let reason = denied.to_string();
+            audit.denied(&reason);
+            Err((StatusCode::FORBIDDEN, reason).into_response())
+        }
+        Err(err) => {
+            let reason = err.to_string();
+            audit.failed(&reason);
+            Err((StatusCode::BAD_GATEWAY, reason).into_response())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Bytes, routing::post, Json};
+    use http_body_util::{BodyExt, Empty, Full};
+    use hyper::{client::conn::http2, server::conn::http1};
+    use rustls::{
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        RootCertStore,
+    };
+    use serde_json::{json, Value};
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+    use super::*;
+
+    async fn spawn_proxy(config: Config) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(serve(listener, None, config, std::future::pending()));
+
+        addr
+    }
+
+    /// Serves SOCKS5 only, returning its address.
+    async fn spawn_socks_proxy(config: Config) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socks = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = socks.local_addr().unwrap();
+
+        tokio::spawn(serve(listener, Some(socks), config, std::future::pending()));
+
+        addr
+    }
+
+    /// Goes through the SOCKS5 handshake as `username`, returning the reply code and the stream
+    /// that carries the tunnel.
+    async fn open_socks_tunnel(
+        proxy: SocketAddr,
+        (username, password): (&str, &str),
+        target: SocketAddr,
+    ) -> (u8, TcpStream) {
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+
+        // Offering username/password only
+        stream.write_all(&[5, 1, 2]).await.unwrap();
+        let mut method = [0; 2];
+        stream.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [5, 2]);
+
+        let mut auth = vec![1, username.len() as u8];
+        auth.extend_from_slice(username.as_bytes());
+        auth.push(password.len() as u8);
+        auth.extend_from_slice(password.as_bytes());
+        stream.write_all(&auth).await.unwrap();
+
+        let mut status = [0; 2];
+        stream.read_exact(&mut status).await.unwrap();
+        if status[1] != 0 {
+            return (0xff, stream);
+        }
+
+        let SocketAddr::V4(target) = target else {
+            panic!("IPv4 targets only");
+        };
+        let mut request = vec![5, 1, 0, 1];
+        request.extend_from_slice(&target.ip().octets());
+        request.extend_from_slice(&target.port().to_be_bytes());
+        stream.write_all(&request).await.unwrap();
+
+        // Version, reply, reserved, an IPv4 address and a port
+        let mut reply = [0; 10];
+        stream.read_exact(&mut reply).await.unwrap();
+
+        (reply[1], stream)
+    }
+
+    // Echoes what it got back as JSON, to see what the proxy forwarded
+    async fn spawn_upstream() -> SocketAddr {
+        async fn echo(headers: HeaderMap, body: String) -> impl IntoResponse {
+            let headers: serde_json::Map<_, _> = headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), json!(value.to_str().unwrap())))
+                .collect();
+
+            (
+                [("keep-alive", "timeout=5"), ("x-upstream", "yes")],
+                Json(json!({ "headers": headers, "body": body })),
+            )
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/echo", post(echo));
+
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        addr
+    }
+
+    // Sends every byte back, as the target of tunnels
+    async fn spawn_echo() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = stream.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+
+        addr
+    }
+
+    // Serves HTTPS for `localhost` with a self-signed certificate, which is returned as PEM
+    async fn spawn_tls_upstream() -> (SocketAddr, String) {
+        let key = rcgen::KeyPair::generate().unwrap();
+        let cert = rcgen::CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .self_signed(&key)
+            .unwrap();
+
+        let config = rustls::ServerConfig::builder_with_provider(mitm::crypto_provider())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![cert.der().clone()],
+                PrivateKeyDer::Pkcs8(key.serialize_der().into()),
+            )
+            .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+
+                tokio::spawn(async move {
+                    let Ok(stream) = acceptor.accept(stream).await else {
+                        return;
+                    };
+
+                    let service = hyper::service::service_fn(|req: Request<Incoming>| async move {
+                        let path = req.uri().path().to_string();
+                        let body = req.into_body().collect().await?.to_bytes();
+                        let reply = format!("{path}: {}", String::from_utf8_lossy(&body));
+
+                        Ok::<_, hyper::Error>(hyper::Response::new(Full::new(Bytes::from(reply))))
+                    });
+
+                    let _ = http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await;
+                });
+            }
+        });
+
+        (addr, cert.pem())
+    }
+
+    /// Sends a CONNECT, returning the status and the stream that carries the tunnel.
+    async fn open_tunnel(proxy: SocketAddr, target: SocketAddr) -> (StatusCode, TcpStream) {
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        stream
+            .write_all(format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+
+        // Byte by byte, so nothing sent through the tunnel is read as part of the head
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(stream.read_u8().await.unwrap());
+        }
+
+        let head = String::from_utf8(head).unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+
+        (status, stream)
+    }
+
+    /// Sends a CONNECT over HTTP/2, with `protocol` for an extended CONNECT, returning the status
+    /// and the stream that carries the tunnel.
+    async fn open_h2_tunnel(
+        proxy: SocketAddr,
+        uri: &str,
+        protocol: Option<&'static str>,
+    ) -> (StatusCode, impl AsyncRead + AsyncWrite + Unpin) {
+        let stream = TcpStream::connect(proxy).await.unwrap();
+        let (mut sender, conn) = http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+            .await
+            .unwrap();
+        tokio::spawn(conn);
+
+        let mut req = Request::connect(uri).body(Empty::<Bytes>::new()).unwrap();
+        if let Some(protocol) = protocol {
+            req.extensions_mut()
+                .insert(hyper::ext::Protocol::from_static(protocol));
+        }
+
+        let res = sender.send_request(req).await.unwrap();
+        let status = res.status();
+
+        (status, TokioIo::new(hyper::upgrade::on(res).await.unwrap()))
+    }
+
+    async fn assert_echoes<S>(stream: &mut S, message: &[u8])
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        stream.write_all(message).await.unwrap();
+
+        let mut echoed = vec![0; message.len()];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(echoed, message);
+    }
+
+    fn client_via(proxy: reqwest::Proxy) -> reqwest::Client {
+        reqwest::Client::builder().proxy(proxy).build().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_forward() {
+        let upstream = spawn_upstream().await;
+        let proxy = spawn_proxy(Config::default()).await;
+        let client = client_via(reqwest::Proxy::http(format!("http://{proxy}")).unwrap());
+
+        let res = client
+            .post(format!("http://{upstream}/echo"))
+            .header("connection", "x-secret")
+            .header("x-secret", "only for the proxy")
+            .header("x-end-to-end", "kept")
+            .body("hello")
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["via"], "1.1 http-proxy");
+        assert_eq!(res.headers()["x-upstream"], "yes");
+        assert!(res.headers().get("keep-alive").is_none());
+
+        let echo: Value = res.json().await.unwrap();
+        assert_eq!(echo["body"], "hello");
+        assert_eq!(echo["headers"]["via"], "1.1 http-proxy");
+        assert_eq!(echo["headers"]["x-end-to-end"], "kept");
+        assert!(echo["headers"].get("x-secret").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_forward_requires_credentials() {
+        let upstream = spawn_upstream().await;
+        let proxy = spawn_proxy(Config {
+            credentials: Some(Credentials {
+                username: "alice".to_string(),
+                password: "secret".to_string(),
+            }),
+            ..Config::default()
+        })
+        .await;
+        let url = format!("http://{upstream}/echo");
+
+        let client = client_via(reqwest::Proxy::http(format!("http://{proxy}")).unwrap());
+        let res = client.post(&url).send().await.unwrap();
+
+        assert_eq!(res.status(), StatusCode::PROXY_AUTHENTICATION_REQUIRED);
+        assert_eq!(
+            res.headers()["proxy-authenticate"],
+            r#"Basic realm="proxy""#
+        );
 
-    Ok(())
+        let client = client_via(
+            reqwest::Proxy::http(format!("http://{proxy}"))
+                .unwrap()
+                .basic_auth("alice", "secret"),
+        );
+        let res = client.post(&url).send().await.unwrap();
+
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // The credentials are for the proxy only
+        let echo: Value = res.json().await.unwrap();
+        assert!(echo["headers"].get("proxy-authorization").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_forward_denied_target() {
+        let upstream = spawn_upstream().await;
+        let proxy = spawn_proxy(Config {
+            access: AccessControl {
+                deny: access::parse_list("127.0.0.0/8").unwrap(),
+                ..Default::default()
+            },
+            ..Config::default()
+        })
+        .await;
+        let client = client_via(reqwest::Proxy::http(format!("http://{proxy}")).unwrap());
+
+        let res = client
+            .post(format!("http://{upstream}/echo"))
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_forward_checks_lookups() {
+        let upstream = spawn_upstream().await;
+
+        // As if `localhost` resolved to an allowed address when the request was admitted, but
+        // to a denied one when the client connects
+        let access = AccessControl {
+            deny: access::parse_list("127.0.0.0/8, ::1").unwrap(),
+            ..Default::default()
+        };
+        let url = format!("http://localhost:{}/echo", upstream.port());
+        let req = || Request::post(&url).body(Body::from("hello")).unwrap();
+
+        let client = forward::client(Duration::from_secs(5), access);
+        let res = forward::forward(client, req()).await;
+
+        assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+
+        let client = forward::client(Duration::from_secs(5), AccessControl::default());
+        let res = forward::forward(client, req()).await;
+
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_tunnel() {
+        let echo = spawn_echo().await;
+        let proxy = spawn_proxy(Config::default()).await;
+
+        let (status, mut stream) = open_tunnel(proxy, echo).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_echoes(&mut stream, b"ping").await;
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_http2() {
+        let echo = spawn_echo().await;
+        let proxy = spawn_proxy(Config::default()).await;
+
+        let (status, mut stream) = open_h2_tunnel(proxy, &echo.to_string(), None).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_echoes(&mut stream, b"ping").await;
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_http2_extended_connect() {
+        let echo = spawn_echo().await;
+        let proxy = spawn_proxy(Config::default()).await;
+
+        let (status, mut stream) =
+            open_h2_tunnel(proxy, &format!("http://{echo}/"), Some("connect-tcp")).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_echoes(&mut stream, b"ping").await;
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_limit() {
+        let echo = spawn_echo().await;
+        let proxy = spawn_proxy(Config {
+            max_tunnels: 1,
+            ..Config::default()
+        })
+        .await;
+
+        let (status, _first) = open_tunnel(proxy, echo).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _) = open_tunnel(proxy, echo).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_idle_timeout() {
+        let echo = spawn_echo().await;
+        let proxy = spawn_proxy(Config {
+            idle_timeout: Duration::from_millis(200),
+            ..Config::default()
+        })
+        .await;
+
+        let (_, mut stream) = open_tunnel(proxy, echo).await;
+        assert_echoes(&mut stream, b"ping").await;
+
+        // The proxy closes the tunnel once it's quiet for long enough
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut [0; 16]))
+            .await
+            .expect("tunnel closed in time")
+            .unwrap();
+        assert_eq!(read, 0);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_tunnels() {
+        let echo = spawn_echo().await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve(listener, None, Config::default(), async move {
+            let _ = shutdown_rx.await;
+        }));
+
+        let (_, mut stream) = open_tunnel(proxy, echo).await;
+        shutdown_tx.send(()).unwrap();
+
+        // Open tunnels keep working while new clients are turned away
+        assert_echoes(&mut stream, b"still there").await;
+
+        let mut refused = false;
+        for _ in 0..50 {
+            if TcpStream::connect(proxy).await.is_err() {
+                refused = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(refused, "proxy still accepts connections");
+
+        drop(stream);
+
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("proxy shut down once the tunnel closed")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_socks_tunnel() {
+        let echo = spawn_echo().await;
+        let proxy = spawn_socks_proxy(Config {
+            credentials: Some(Credentials {
+                username: "alice".to_string(),
+                password: "secret".to_string(),
+            }),
+            ..Config::default()
+        })
+        .await;
+
+        let (reply, _) = open_socks_tunnel(proxy, ("alice", "wrong"), echo).await;
+        assert_eq!(reply, 0xff);
+
+        let (reply, mut stream) = open_socks_tunnel(proxy, ("alice", "secret"), echo).await;
+        assert_eq!(reply, 0);
+        assert_echoes(&mut stream, b"ping").await;
+    }
+
+    #[tokio::test]
+    async fn test_socks_denied_target() {
+        let echo = spawn_echo().await;
+        let proxy = spawn_socks_proxy(Config {
+            credentials: Some(Credentials {
+                username: "alice".to_string(),
+                password: "secret".to_string(),
+            }),
+            access: AccessControl {
+                ports: vec![443],
+                ..Default::default()
+            },
+            ..Config::default()
+        })
+        .await;
+
+        // Not allowed by ruleset
+        let (reply, _) = open_socks_tunnel(proxy, ("alice", "secret"), echo).await;
+        assert_eq!(reply, 2);
+    }
+
+    #[tokio::test]
+    async fn test_mitm() {
+        let (upstream, upstream_cert) = spawn_tls_upstream().await;
+
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from_pem_slice(upstream_cert.as_bytes()).unwrap())
+            .unwrap();
+
+        let (ca_cert, ca_key) = mitm::generate_ca().unwrap();
+        let proxy = spawn_proxy(Config {
+            mitm: Some(Arc::new(Mitm::new(&ca_cert, &ca_key, roots).unwrap())),
+            ..Config::default()
+        })
+        .await;
+
+        // Only trusting the proxy's CA, so the request can't succeed without interception
+        let client = reqwest::Client::builder()
+            .proxy(reqwest::Proxy::https(format!("http://{proxy}")).unwrap())
+            .tls_built_in_root_certs(false)
+            .add_root_certificate(reqwest::Certificate::from_pem(ca_cert.as_bytes()).unwrap())
+            .build()
+            .unwrap();
+
+        let res = client
+            .post(format!("https://localhost:{}/hello", upstream.port()))
+            .body("world")
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.text().await.unwrap(), "/hello: world");
+    }
+
+    #[test]
+    fn test_credentials() {
+        let credentials = credentials(Some("user".into()), Some("pass".into())).unwrap();
+
+        assert!(credentials.matches("user", "pass"));
+        assert!(super::credentials(None, None).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "have to be set together")]
+    fn test_credentials_half_set() {
+        credentials(Some("user".into()), None);
+    }
 }
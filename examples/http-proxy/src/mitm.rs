@@ -0,0 +1,372 @@
+//! Opt-in TLS interception, to see what goes through tunnels to our own services.
+//!
+//! The proxy acts as a certificate authority that clients have to trust. Its certificate and key
+//! are created on first run and kept in a directory, `ca.pem` in there is what clients need. For
+//! every host a certificate signed by it is minted on the fly, while the proxy makes its own TLS
+//! connection to the real server.
+//!
+//! Bodies are buffered to be logged, so this is for debugging only.
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fs,
+    io::{self, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::body::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    body::Incoming, client::conn::http1 as client_http1, server::conn::http1, Request, Response,
+    StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use rcgen::{
+    BasicConstraints, CertificateParams, DistinguishedName, DnType, IsCa, Issuer, KeyPair,
+    KeyUsagePurpose,
+};
+use rustls::{
+    crypto::CryptoProvider,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+    ClientConfig, RootCertStore, ServerConfig,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    sync::Mutex as AsyncMutex,
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::{
+    audit::Audit,
+    tunnel::{Counted, Side, Traffic},
+};
+
+const CA_CERT_FILE: &str = "ca.pem";
+const CA_KEY_FILE: &str = "ca.key.pem";
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+pub struct Mitm {
+    ca_cert: CertificateDer<'static>,
+    issuer: Issuer<'static, KeyPair>,
+    // Minted certificates are reused for every tunnel to the same host
+    hosts: Mutex<HashMap<String, Arc<ServerConfig>>>,
+    upstream: Arc<ClientConfig>,
+}
+
+/// Explicitly ring, as other crates in the build may enable a second provider for rustls.
+pub fn crypto_provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// A new CA certificate and key, both PEM encoded.
+pub fn generate_ca() -> Result<(String, String), rcgen::Error> {
+    let key = KeyPair::generate()?;
+
+    let mut params = CertificateParams::default();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.key_usages = vec![
+        KeyUsagePurpose::KeyCertSign,
+        KeyUsagePurpose::CrlSign,
+        KeyUsagePurpose::DigitalSignature,
+    ];
+    params.distinguished_name = DistinguishedName::new();
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "http-proxy interception CA");
+
+    let cert = params.self_signed(&key)?;
+
+    Ok((cert.pem(), key.serialize_pem()))
+}
+
+/// The public roots, plus the certificates in `extra_roots` if set, for servers with a
+/// certificate of their own CA.
+pub fn upstream_roots(extra_roots: Option<&Path>) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+
+    if let Some(path) = extra_roots {
+        for cert in CertificateDer::pem_file_iter(path).map_err(io::Error::other)? {
+            roots
+                .add(cert.map_err(io::Error::other)?)
+                .map_err(io::Error::other)?;
+        }
+    }
+
+    Ok(roots)
+}
+
+impl Mitm {
+    /// Loads the CA from `dir`, creating it on first run. Servers are verified against `roots`.
+    pub fn load_or_create(dir: &Path, roots: RootCertStore) -> io::Result<Self> {
+        let cert_path = dir.join(CA_CERT_FILE);
+        let key_path = dir.join(CA_KEY_FILE);
+
+        if !cert_path.exists() {
+            let (cert, key) = generate_ca().map_err(io::Error::other)?;
+
+            fs::create_dir_all(dir)?;
+
+            // Both are moved into place once written, the certificate last. A run interrupted
+            // before that leaves no certificate and the next one starts over
+            let key_tmp = key_path.with_extension("pem.tmp");
+            let cert_tmp = cert_path.with_extension("pem.tmp");
+
+            write_private(&key_tmp, &key)?;
+            fs::write(&cert_tmp, cert)?;
+            fs::rename(&key_tmp, &key_path)?;
+            fs::rename(&cert_tmp, &cert_path)?;
+
+            tracing::info!(
+                "Created a CA for TLS interception, clients have to trust {}",
+                cert_path.display()
+            );
+        }
+
+        Self::new(
+            &fs::read_to_string(cert_path)?,
+            &fs::read_to_string(key_path)?,
+            roots,
+        )
+        .map_err(io::Error::other)
+    }
+
+    pub fn new(ca_cert: &str, ca_key: &str, roots: RootCertStore) -> Result<Self, BoxError> {
+        let key = KeyPair::from_pem(ca_key)?;
+        let issuer = Issuer::from_ca_cert_pem(ca_cert, key)?;
+
+        let mut upstream = ClientConfig::builder_with_provider(crypto_provider())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        upstream.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        Ok(Mitm {
+            ca_cert: CertificateDer::from_pem_slice(ca_cert.as_bytes())?,
+            issuer,
+            hosts: Mutex::new(HashMap::new()),
+            upstream: Arc::new(upstream),
+        })
+    }
+
+    /// Config presenting a certificate for `host`, minted the first time it's asked for.
+    fn server_config(&self, host: &str) -> Result<Arc<ServerConfig>, BoxError> {
+        if let Some(config) = self.hosts.lock().unwrap().get(host) {
+            return Ok(config.clone());
+        }
+
+        let key = KeyPair::generate()?;
+        let mut params = CertificateParams::new(vec![host.to_string()])?;
+        params
+            .distinguished_name
+            .push(DnType::CommonName, host.to_string());
+        let cert = params.signed_by(&key, &self.issuer)?;
+
+        let mut config = ServerConfig::builder_with_provider(crypto_provider())
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![cert.der().clone(), self.ca_cert.clone()],
+                PrivateKeyDer::Pkcs8(key.serialize_der().into()),
+            )?;
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        let config = Arc::new(config);
+        self.hosts
+            .lock()
+            .unwrap()
+            .insert(host.to_string(), config.clone());
+
+        Ok(config)
+    }
+
+    /// Terminates TLS from `client`, opens a TLS connection to `host` over `server`, and passes
+    /// requests and responses between them, logging each.
+    pub async fn intercept<T>(
+        &self,
+        client: T,
+        server: TcpStream,
+        host: &str,
+        idle_timeout: Duration,
+        audit: Audit,
+    ) where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let traffic = Arc::new(Traffic::new());
+        let client = Counted::new(client, Side::Client, traffic.clone());
+        let server = Counted::new(server, Side::Server, traffic.clone());
+
+        let reason = tokio::select! {
+            res = self.relay(client, server, host) => match res {
+                Ok(()) => "closed".to_string(),
+                Err(err) => {
+                    tracing::warn!("interception of {} failed: {}", host, err);
+                    format!("interception failed: {err}")
+                }
+            },
+            _ = traffic.idle(idle_timeout) => "idle timeout".to_string(),
+        };
+
+        audit.closed(traffic.sent(), traffic.received(), reason);
+    }
+
+    async fn relay<C, S>(&self, client: C, server: S, host: &str) -> Result<(), BoxError>
+    where
+        C: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let client = TlsAcceptor::from(self.server_config(host)?)
+            .accept(client)
+            .await?;
+
+        let server_name = ServerName::try_from(host.to_string())?;
+        let server = TlsConnector::from(self.upstream.clone())
+            .connect(server_name, server)
+            .await?;
+
+        let (sender, upstream) = client_http1::handshake(TokioIo::new(server)).await?;
+        let sender = Arc::new(AsyncMutex::new(sender));
+
+        let host = host.to_string();
+        let service = hyper::service::service_fn(move |req: Request<Incoming>| {
+            let sender = sender.clone();
+            let host = host.clone();
+            async move {
+                Ok::<_, Infallible>(match exchange(&sender, &host, req).await {
+                    Ok(res) => res,
+                    Err(err) => {
+                        tracing::warn!("request to {} failed: {}", host, err);
+
+                        let mut res = Response::new(Full::from("upstream request failed"));
+                        *res.status_mut() = StatusCode::BAD_GATEWAY;
+                        res
+                    }
+                })
+            }
+        });
+
+        let downstream = http1::Builder::new()
+            .preserve_header_case(true)
+            .serve_connection(TokioIo::new(client), service);
+
+        // Whichever side closes first ends the tunnel
+        tokio::select! {
+            res = downstream => res?,
+            res = upstream => res?,
+        }
+
+        Ok(())
+    }
+}
+
+// Only readable by the owner, anyone with the key can impersonate any site to our clients
+fn write_private(path: &Path, contents: &str) -> io::Result<()> {
+    // Never write into an existing file, whoever created it chose its permissions
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    options.open(path)?.write_all(contents.as_bytes())
+}
+
+/// Sends one decrypted request upstream, logging it and its response.
+async fn exchange(
+    sender: &AsyncMutex<client_http1::SendRequest<Full<Bytes>>>,
+    host: &str,
+    req: Request<Incoming>,
+) -> Result<Response<Full<Bytes>>, BoxError> {
+    tracing::info!(
+        "{} {} {} {:?}",
+        host,
+        req.method(),
+        req.uri(),
+        req.headers()
+    );
+
+    let (parts, body) = req.into_parts();
+    let body = body.collect().await?.to_bytes();
+    log_body(host, "request", &body);
+    let req = Request::from_parts(parts, Full::new(body));
+
+    let res = sender.lock().await.send_request(req).await?;
+
+    tracing::info!("{} {} {:?}", host, res.status(), res.headers());
+
+    let (parts, body) = res.into_parts();
+    let body = body.collect().await?.to_bytes();
+    log_body(host, "response", &body);
+
+    Ok(Response::from_parts(parts, Full::new(body)))
+}
+
+fn log_body(host: &str, direction: &str, body: &[u8]) {
+    if let Ok(body) = std::str::from_utf8(body) {
+        tracing::info!("{} {} body: {}", host, direction, body);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ca_is_created_once() {
+        let dir = std::env::temp_dir().join(format!("http-proxy-ca-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        Mitm::load_or_create(&dir, RootCertStore::empty()).unwrap();
+        let cert = fs::read_to_string(dir.join(CA_CERT_FILE)).unwrap();
+
+        // The second run finds the CA clients already trust
+        Mitm::load_or_create(&dir, RootCertStore::empty()).unwrap();
+        assert_eq!(fs::read_to_string(dir.join(CA_CERT_FILE)).unwrap(), cert);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_interrupted_first_run() {
+        let dir = std::env::temp_dir().join(format!("http-proxy-ca-tmp-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        // Stopped after writing the key, but before the certificate
+        let (_, key) = generate_ca().unwrap();
+        fs::write(dir.join(CA_KEY_FILE), &key).unwrap();
+        fs::write(dir.join("ca.key.pem.tmp"), &key).unwrap();
+
+        Mitm::load_or_create(&dir, RootCertStore::empty()).unwrap();
+
+        assert_ne!(fs::read_to_string(dir.join(CA_KEY_FILE)).unwrap(), key);
+        assert!(dir.join(CA_CERT_FILE).exists());
+        assert!(!dir.join("ca.key.pem.tmp").exists());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = fs::metadata(dir.join(CA_KEY_FILE))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        Mitm::load_or_create(&dir, RootCertStore::empty()).unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,398 @@
+//! A SOCKS5 listener (RFC 1928) for clients that don't speak HTTP proxy, with username/password
+//! authentication (RFC 1929). Only `CONNECT` is supported.
+
+use std::{
+    fmt, io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{
+    access::TargetError, audit::Audit, open_target, tunnel, Config, OpenError, ProxyState,
+};
+
+const VERSION: u8 = 5;
+// Version of the username/password subnegotiation
+const AUTH_VERSION: u8 = 1;
+
+const NO_AUTH: u8 = 0x00;
+const USERNAME_PASSWORD: u8 = 0x02;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+
+const CONNECT: u8 = 0x01;
+
+const IPV4: u8 = 0x01;
+const DOMAIN: u8 = 0x03;
+const IPV6: u8 = 0x04;
+
+// Reply codes
+const SUCCEEDED: u8 = 0x00;
+const GENERAL_FAILURE: u8 = 0x01;
+const NOT_ALLOWED: u8 = 0x02;
+const HOST_UNREACHABLE: u8 = 0x04;
+const CONNECTION_REFUSED: u8 = 0x05;
+const TTL_EXPIRED: u8 = 0x06;
+const COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+const UNSPECIFIED: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
+// Clients that don't finish the handshake in time are dropped, they hold a connection slot
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// Not a SOCKS5 client, or a broken one.
+    Protocol(&'static str),
+    /// A valid request for something that isn't supported, answered with this reply code.
+    Unsupported(u8),
+    Unauthorized(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{err}"),
+            Error::Protocol(reason) => write!(f, "{reason}"),
+            Error::Unsupported(code) => write!(f, "unsupported request, replied {code:#04x}"),
+            Error::Unauthorized(user) => write!(f, "invalid credentials for {user}"),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+/// Where a client wants to connect to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    pub host: String,
+    pub port: u16,
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// Serves one SOCKS5 client, tunneling it to its target like a CONNECT request.
+pub async fn handle(mut stream: TcpStream, client: SocketAddr, state: Arc<ProxyState>) {
+    let (target, user) = match tokio::time::timeout(
+        HANDSHAKE_TIMEOUT,
+        handshake(&mut stream, &state.config),
+    )
+    .await
+    {
+        Ok(Ok(handshake)) => handshake,
+        Ok(Err(Error::Unauthorized(user))) => {
+            Audit::new(client, "unknown")
+                .user(Some(&user))
+                .denied("invalid credentials");
+            return;
+        }
+        Ok(Err(err)) => {
+            tracing::debug!("SOCKS handshake with {} failed: {}", client, err);
+            return;
+        }
+        Err(_) => {
+            tracing::debug!("SOCKS handshake with {} timed out", client);
+            return;
+        }
+    };
+
+    let audit = Audit::new(client, target.to_string()).user(user.as_deref());
+
+    let addrs = match state.config.access.resolve(&target.host, target.port).await {
+        Ok(addrs) => addrs,
+        Err(TargetError::Denied(denied)) => {
+            audit.denied(denied);
+            let _ = reply(&mut stream, NOT_ALLOWED, UNSPECIFIED).await;
+            return;
+        }
+        Err(err) => {
+            audit.failed(err);
+            let _ = reply(&mut stream, HOST_UNREACHABLE, UNSPECIFIED).await;
+            return;
+        }
+    };
+
+    let (permit, server) = match open_target(&state, &addrs).await {
+        Ok(opened) => opened,
+        Err(err) => {
+            let code = match &err {
+                OpenError::TooManyTunnels => GENERAL_FAILURE,
+                OpenError::Timeout => TTL_EXPIRED,
+                OpenError::Connect(err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+                    CONNECTION_REFUSED
+                }
+                OpenError::Connect(_) => HOST_UNREACHABLE,
+            };
+            err.audit(audit);
+            let _ = reply(&mut stream, code, UNSPECIFIED).await;
+            return;
+        }
+    };
+
+    let bound = server.local_addr().unwrap_or(UNSPECIFIED);
+    if let Err(err) = reply(&mut stream, SUCCEEDED, bound).await {
+        audit.failed(err);
+        return;
+    }
+
+    tunnel::tunnel(stream, server, state.config.idle_timeout, audit).await;
+
+    drop(permit);
+}
+
+/// Negotiates authentication and reads the request, returning the target and the username.
+async fn handshake<S>(stream: &mut S, config: &Config) -> Result<(Target, Option<String>), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let methods = read_methods(stream).await?;
+
+    let user = match &config.credentials {
+        Some(credentials) => {
+            if !methods.contains(&USERNAME_PASSWORD) {
+                stream.write_all(&[VERSION, NO_ACCEPTABLE_METHODS]).await?;
+                return Err(Error::Protocol("client can't authenticate"));
+            }
+            stream.write_all(&[VERSION, USERNAME_PASSWORD]).await?;
+
+            let (username, password) = read_credentials(stream).await?;
+
+            if !credentials.matches(&username, &password) {
+                stream.write_all(&[AUTH_VERSION, 1]).await?;
+                return Err(Error::Unauthorized(username));
+            }
+            stream.write_all(&[AUTH_VERSION, 0]).await?;
+
+            Some(username)
+        }
+        None => {
+            if !methods.contains(&NO_AUTH) {
+                stream.write_all(&[VERSION, NO_ACCEPTABLE_METHODS]).await?;
+                return Err(Error::Protocol("client insists on authenticating"));
+            }
+            stream.write_all(&[VERSION, NO_AUTH]).await?;
+
+            None
+        }
+    };
+
+    match read_request(stream).await {
+        Ok(target) => Ok((target, user)),
+        Err(Error::Unsupported(code)) => {
+            reply(stream, code, UNSPECIFIED).await?;
+            Err(Error::Unsupported(code))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// The authentication methods the client offers.
+async fn read_methods<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<u8>, Error> {
+    if reader.read_u8().await? != VERSION {
+        return Err(Error::Protocol("not SOCKS5"));
+    }
+
+    let mut methods = vec![0; reader.read_u8().await? as usize];
+    reader.read_exact(&mut methods).await?;
+
+    Ok(methods)
+}
+
+async fn read_credentials<R: AsyncRead + Unpin>(reader: &mut R) -> Result<(String, String), Error> {
+    if reader.read_u8().await? != AUTH_VERSION {
+        return Err(Error::Protocol("unknown authentication version"));
+    }
+
+    let username = read_string(reader).await?;
+    let password = read_string(reader).await?;
+
+    Ok((username, password))
+}
+
+async fn read_request<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Target, Error> {
+    let mut header = [0; 4];
+    reader.read_exact(&mut header).await?;
+    let [version, command, _reserved, address_type] = header;
+
+    if version != VERSION {
+        return Err(Error::Protocol("not SOCKS5"));
+    }
+
+    if command != CONNECT {
+        return Err(Error::Unsupported(COMMAND_NOT_SUPPORTED));
+    }
+
+    let host = match address_type {
+        IPV4 => {
+            let mut octets = [0; 4];
+            reader.read_exact(&mut octets).await?;
+            Ipv4Addr::from(octets).to_string()
+        }
+        IPV6 => {
+            let mut octets = [0; 16];
+            reader.read_exact(&mut octets).await?;
+            Ipv6Addr::from(octets).to_string()
+        }
+        DOMAIN => read_string(reader).await?,
+        _ => return Err(Error::Unsupported(ADDRESS_TYPE_NOT_SUPPORTED)),
+    };
+
+    let port = reader.read_u16().await?;
+
+    Ok(Target { host, port })
+}
+
+// One length byte, followed by that many bytes of UTF-8
+async fn read_string<R: AsyncRead + Unpin>(reader: &mut R) -> Result<String, Error> {
+    let mut bytes = vec![0; reader.read_u8().await? as usize];
+    reader.read_exact(&mut bytes).await?;
+
+    String::from_utf8(bytes).map_err(|_| Error::Protocol("invalid UTF-8"))
+}
+
+async fn reply<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    code: u8,
+    bound: SocketAddr,
+) -> io::Result<()> {
+    writer.write_all(&encode_reply(code, bound)).await
+}
+
+fn encode_reply(code: u8, bound: SocketAddr) -> Vec<u8> {
+    let mut reply = vec![VERSION, code, 0];
+
+    match bound {
+        SocketAddr::V4(addr) => {
+            reply.push(IPV4);
+            reply.extend_from_slice(&addr.ip().octets());
+        }
+        SocketAddr::V6(addr) => {
+            reply.push(IPV6);
+            reply.extend_from_slice(&addr.ip().octets());
+        }
+    }
+    reply.extend_from_slice(&bound.port().to_be_bytes());
+
+    reply
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_methods() {
+        assert_eq!(
+            read_methods(&mut &[5, 2, NO_AUTH, USERNAME_PASSWORD][..])
+                .await
+                .unwrap(),
+            vec![NO_AUTH, USERNAME_PASSWORD]
+        );
+
+        // SOCKS4
+        assert!(matches!(
+            read_methods(&mut &[4, 1, 0][..]).await,
+            Err(Error::Protocol(_))
+        ));
+        // Fewer methods than announced
+        assert!(matches!(
+            read_methods(&mut &[5, 2, 0][..]).await,
+            Err(Error::Io(err)) if err.kind() == io::ErrorKind::UnexpectedEof
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_read_credentials() {
+        let mut message = vec![AUTH_VERSION, 5];
+        message.extend_from_slice(b"alice");
+        message.push(6);
+        message.extend_from_slice(b"secret");
+
+        assert_eq!(
+            read_credentials(&mut &message[..]).await.unwrap(),
+            ("alice".to_string(), "secret".to_string())
+        );
+
+        assert!(matches!(
+            read_credentials(&mut &[AUTH_VERSION, 2, 0xff, 0xfe, 0][..]).await,
+            Err(Error::Protocol(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_read_request() {
+        assert_eq!(
+            read_request(&mut &[5, CONNECT, 0, IPV4, 192, 0, 2, 1, 0x01, 0xbb][..])
+                .await
+                .unwrap(),
+            Target {
+                host: "192.0.2.1".to_string(),
+                port: 443
+            }
+        );
+
+        let mut domain = vec![5, CONNECT, 0, DOMAIN, 11];
+        domain.extend_from_slice(b"example.com");
+        domain.extend_from_slice(&80u16.to_be_bytes());
+        assert_eq!(
+            read_request(&mut &domain[..]).await.unwrap(),
+            Target {
+                host: "example.com".to_string(),
+                port: 80
+            }
+        );
+
+        let mut ipv6 = vec![5, CONNECT, 0, IPV6];
+        ipv6.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        ipv6.extend_from_slice(&8080u16.to_be_bytes());
+        let target = read_request(&mut &ipv6[..]).await.unwrap();
+        assert_eq!(target.host, "::1");
+        assert_eq!(target.to_string(), "[::1]:8080");
+
+        // BIND
+        assert!(matches!(
+            read_request(&mut &[5, 2, 0, IPV4, 127, 0, 0, 1, 0, 80][..]).await,
+            Err(Error::Unsupported(COMMAND_NOT_SUPPORTED))
+        ));
+        assert!(matches!(
+            read_request(&mut &[5, CONNECT, 0, 9, 0, 0][..]).await,
+            Err(Error::Unsupported(ADDRESS_TYPE_NOT_SUPPORTED))
+        ));
+    }
+
+    #[test]
+    fn test_encode_reply() {
+        assert_eq!(
+            encode_reply(SUCCEEDED, "127.0.0.1:1080".parse().unwrap()),
+            vec![5, SUCCEEDED, 0, IPV4, 127, 0, 0, 1, 0x04, 0x38]
+        );
+        assert_eq!(
+            encode_reply(NOT_ALLOWED, "[::1]:0".parse().unwrap()),
+            [
+                &[5, NOT_ALLOWED, 0, IPV6][..],
+                &Ipv6Addr::LOCALHOST.octets(),
+                &[0, 0]
+            ]
+            .concat()
+        );
+    }
+}
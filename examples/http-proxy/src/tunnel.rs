@@ -0,0 +1,167 @@
+//! Copying bytes between a client and the target of its tunnel.
+
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+    time::Instant,
+};
+
+use crate::audit::Audit;
+
+/// Copies between `client` and `server` until either side closes or neither sends anything for
+/// `idle_timeout`, then logs the bytes sent each way.
+pub async fn tunnel<T>(client: T, server: TcpStream, idle_timeout: Duration, audit: Audit)
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let traffic = Arc::new(Traffic::new());
+    let mut client = Counted::new(client, Side::Client, traffic.clone());
+    let mut server = Counted::new(server, Side::Server, traffic.clone());
+
+    // Write/read data bidirectionally between the client and the server
+    let reason = tokio::select! {
+        res = tokio::io::copy_bidirectional(&mut client, &mut server) => match res {
+            Ok(_) => "closed".to_string(),
+            Err(err) => {
+                tracing::warn!("server io error {:?}", err);
+                format!("io error: {err}")
+            }
+        },
+        _ = traffic.idle(idle_timeout) => "idle timeout".to_string(),
+    };
+
+    tracing::debug!(
+        "client wrote {} bytes and received {} bytes",
+        traffic.sent(),
+        traffic.received()
+    );
+
+    audit.closed(traffic.sent(), traffic.received(), reason);
+}
+
+/// Bytes that went through a tunnel, and when they last did.
+pub struct Traffic {
+    started: Instant,
+    // Since `started`, so it fits an atomic
+    last_millis: AtomicU64,
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+impl Traffic {
+    pub fn new() -> Self {
+        Traffic {
+            started: Instant::now(),
+            last_millis: AtomicU64::new(0),
+            sent: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+        }
+    }
+
+    /// From the client to the server.
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    /// From the server to the client.
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    fn record(&self, side: Side, bytes: usize) {
+        let counter = match side {
+            Side::Client => &self.sent,
+            Side::Server => &self.received,
+        };
+        counter.fetch_add(bytes as u64, Ordering::Relaxed);
+
+        let millis = self.started.elapsed().as_millis() as u64;
+        self.last_millis.store(millis, Ordering::Relaxed);
+    }
+
+    /// Completes once nothing went through for `timeout`.
+    pub async fn idle(&self, timeout: Duration) {
+        loop {
+            let last =
+                self.started + Duration::from_millis(self.last_millis.load(Ordering::Relaxed));
+
+            if last.elapsed() >= timeout {
+                return;
+            }
+
+            tokio::time::sleep_until(last + timeout).await;
+        }
+    }
+}
+
+/// Which end of a tunnel a stream connects to.
+#[derive(Debug, Clone, Copy)]
+pub enum Side {
+    Client,
+    Server,
+}
+
+/// Records the bytes read from a stream in [`Traffic`], everything read is passed on to the
+/// other side.
+pub struct Counted<T> {
+    inner: T,
+    side: Side,
+    traffic: Arc<Traffic>,
+}
+
+impl<T> Counted<T> {
+    pub fn new(inner: T, side: Side, traffic: Arc<Traffic>) -> Self {
+        Counted {
+            inner,
+            side,
+            traffic,
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Counted<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+
+        let read = buf.filled().len() - before;
+        if read > 0 {
+            self.traffic.record(self.side, read);
+        }
+
+        poll
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Counted<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
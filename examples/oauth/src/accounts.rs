@@ -0,0 +1,201 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Provider-neutral identity, mapped from a provider's user-info response or from the standard
+/// OIDC claims.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Identity {
+    pub provider: String,
+    pub subject: String,
+    pub name: String,
+    pub email: Option<String>,
+    pub avatar_url: Option<String>,
+}
+
+/// Local account with one or more linked provider identities.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct User {
+    pub id: u64,
+    pub name: String,
+    pub email: Option<String>,
+    pub avatar_url: Option<String>,
+    pub identities: Vec<Identity>,
+}
+
+impl User {
+    pub fn is_linked(&self, provider: &str) -> bool {
+        self.identities
+            .iter()
+            .any(|identity| identity.provider == provider)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum LinkError {
+    // The identity belongs to a different account
+    AlreadyLinked,
+    AccountNotFound,
+}
+
+#[derive(Default)]
+struct Accounts {
+    next_id: u64,
+    users: HashMap<u64, User>,
+    // (provider, subject) -> account id
+    identities: HashMap<(String, String), u64>,
+}
+
+#[derive(Clone, Default)]
+pub struct AccountStore {
+    inner: Arc<RwLock<Accounts>>,
+}
+
+impl AccountStore {
+    pub fn get(&self, id: u64) -> Option<User> {
+        self.inner.read().unwrap().users.get(&id).cloned()
+    }
+
+    /// Returns the account the identity is linked to, creating a new account on first login.
+    pub fn login(&self, identity: Identity) -> User {
+        let mut accounts = self.inner.write().unwrap();
+
+        let key = (identity.provider.clone(), identity.subject.clone());
+
+        if let Some(id) = accounts.identities.get(&key).copied() {
+            let user = accounts.users.get_mut(&id).expect("linked account exists");
+            upsert_identity(user, identity);
+            return user.clone();
+        }
+
+        accounts.next_id += 1;
+        let id = accounts.next_id;
+
+        let user = User {
+            id,
+            name: identity.name.clone(),
+            email: identity.email.clone(),
+            avatar_url: identity.avatar_url.clone(),
+            identities: vec![identity],
+        };
+
+        accounts.identities.insert(key, id);
+        accounts.users.insert(id, user.clone());
+
+        user
+    }
+
+    /// Links the identity to an existing account. Fails if the identity already belongs to a
+    /// different account.
+    pub fn link(&self, id: u64, identity: Identity) -> Result<User, LinkError> {
+        let mut accounts = self.inner.write().unwrap();
+
+        let key = (identity.provider.clone(), identity.subject.clone());
+
+        match accounts.identities.get(&key) {
+            Some(linked_id) if *linked_id != id => return Err(LinkError::AlreadyLinked),
+            _ => {}
+        }
+
+        let user = accounts
+            .users
+            .get_mut(&id)
+            .ok_or(LinkError::AccountNotFound)?;
+
+        upsert_identity(user, identity);
+        let user = user.clone();
+
+        accounts.identities.insert(key, id);
+
+        Ok(user)
+    }
+}
+
+// Refresh the profile data of an identity we have seen before, or add it to the account
+fn upsert_identity(user: &mut User, identity: Identity) {
+    if user.email.is_none() {
+        user.email = identity.email.clone();
+    }
+
+    if user.avatar_url.is_none() {
+        user.avatar_url = identity.avatar_url.clone();
+    }
+
+    match user
+        .identities
+        .iter_mut()
+        .find(|i| i.provider == identity.provider && i.subject == identity.subject)
+    {
+        Some(existing) => *existing = identity,
+        None => user.identities.push(identity),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity(provider: &str, subject: &str) -> Identity {
+        Identity {
+            provider: provider.to_string(),
+            subject: subject.to_string(),
+            name: format!("{provider} user"),
+            email: None,
+            avatar_url: None,
+        }
+    }
+
+    #[test]
+    fn test_login() {
+        let accounts = AccountStore::default();
+
+        let user = accounts.login(identity("github", "1"));
+        assert_eq!(user.name, "github user");
+
+        // The same identity logs into the same account, a different one gets a new account
+        assert_eq!(accounts.login(identity("github", "1")).id, user.id);
+        assert_ne!(accounts.login(identity("github", "2")).id, user.id);
+        assert_ne!(accounts.login(identity("gitlab", "1")).id, user.id);
+    }
+
+    #[test]
+    fn test_link() {
+        let accounts = AccountStore::default();
+
+        let user = accounts.login(identity("github", "1"));
+        let linked = accounts.link(user.id, identity("gitlab", "7")).unwrap();
+
+        assert!(linked.is_linked("github"));
+        assert!(linked.is_linked("gitlab"));
+
+        // Either identity now logs into the account
+        assert_eq!(accounts.login(identity("gitlab", "7")).id, user.id);
+
+        // Linking again is a no-op
+        let relinked = accounts.link(user.id, identity("gitlab", "7")).unwrap();
+        assert_eq!(relinked.identities.len(), 2);
+    }
+
+    #[test]
+    fn test_link_conflict() {
+        let accounts = AccountStore::default();
+
+        let alice = accounts.login(identity("github", "1"));
+        let bob = accounts.login(identity("gitlab", "2"));
+
+        assert_eq!(
+            accounts.link(bob.id, identity("github", "1")).unwrap_err(),
+            LinkError::AlreadyLinked
+        );
+        assert_eq!(
+            accounts.link(42, identity("gitlab", "3")).unwrap_err(),
+            LinkError::AccountNotFound
+        );
+
+        assert!(!accounts.get(bob.id).unwrap().is_linked("github"));
+        assert_eq!(accounts.login(identity("github", "1")).id, alice.id);
+    }
+}
@@ -0,0 +1,49 @@
+use oauth2::{
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode},
+    HttpRequest, HttpResponse,
+};
+
+/// Sends an `oauth2`/`openidconnect` request through the given `reqwest` client.
+///
+/// Used instead of `oauth2::reqwest::async_http_client` so the client can be injected, e.g. to
+/// talk to a local mock provider in tests. `oauth2` still uses the `http` 0.2 types, so the
+/// request and response are converted by hand.
+pub async fn async_http_client(
+    client: &reqwest::Client,
+    request: HttpRequest,
+) -> Result<HttpResponse, reqwest::Error> {
+    // Every `http` 0.2 method is a valid `http` 1.0 method
+    let method = reqwest::Method::from_bytes(request.method.as_str().as_bytes())
+        .expect("valid HTTP method");
+
+    let mut builder = client
+        .request(method, request.url.as_str())
+        .body(request.body);
+
+    for (name, value) in &request.headers {
+        builder = builder.header(name.as_str(), value.as_bytes());
+    }
+
+    let response = builder.send().await?;
+
+    let status_code =
+        StatusCode::from_u16(response.status().as_u16()).expect("valid HTTP status code");
+
+    let headers = response
+        .headers()
+        .iter()
+        .filter_map(|(name, value)| {
+            let name = HeaderName::from_bytes(name.as_str().as_bytes()).ok()?;
+            let value = HeaderValue::from_bytes(value.as_bytes()).ok()?;
+            Some((name, value))
+        })
+        .collect::<HeaderMap>();
+
+    let body = response.bytes().await?.to_vec();
+
+    Ok(HttpResponse {
+        status_code,
+        headers,
+        body,
+    })
+}
@@ -1,26 +1,40 @@
-use std::{convert::Infallible, env};
-
-use anyhow::{anyhow, Context};
+mod accounts;
+mod http_client;
+#[cfg(test)]
+mod mock_provider;
+mod oidc;
+mod providers;
+mod tokens;
+
+use std::{collections::HashMap, env, sync::Arc};
+
+use accounts::{AccountStore, LinkError, User};
+use anyhow::Context;
 use async_session::{MemoryStore, Session, SessionStore};
 use axum::{
-    extract::{FromRef, FromRequestParts, OptionalFromRequestParts, Query, State},
+    extract::{FromRef, FromRequestParts, OptionalFromRequestParts, Path, Query, State},
     http::StatusCode,
     response::{Html, IntoResponse, Redirect, Response},
     routing::get,
-    RequestPartsExt, Router,
-};
-use axum_extra::{headers, typed_header::TypedHeaderRejectionReason, TypedHeader};
-use http::{header, request::Parts, HeaderMap};
-use oauth2::{
-    basic::BasicClient, reqwest::async_http_client, AuthUrl, AuthorizationCode, ClientId,
-    ClientSecret, CsrfToken, RedirectUrl, Scope, TokenResponse, TokenUrl,
+    Json, RequestPartsExt, Router,
 };
-use serde::{Deserialize, Serialize};
+use axum_extra::extract::cookie::{Cookie, Key, PrivateCookieJar, SameSite};
+use http::request::Parts;
+use oauth2::{AccessToken, CsrfToken, PkceCodeVerifier};
+use openidconnect::Nonce;
+use providers::{Provider, Providers};
+use serde::Deserialize;
+use tokens::{SealedTokens, TokenCipher};
 use tokio::net::TcpListener;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 static COOKIE_NAME: &str = "SESSION";
 static CSRF_TOKEN: &str = "csrf_token";
+static PKCE_VERIFIER: &str = "pkce_verifier";
+static NONCE: &str = "nonce";
+static PROVIDER: &str = "provider";
+static ACCOUNT_ID: &str = "account_id";
+static TOKENS: &str = "tokens";
 
 #[tokio::main]
 async fn main() {
@@ -36,21 +50,33 @@ async fn main() {
 
     let store = MemoryStore::new();
 
-    let oauth_client = oauth_client().unwrap();
+    let providers = providers::providers_from_env(reqwest::Client::new())
+        .await
+        .unwrap();
+
+    // Encrypts and authenticates the session cookie, must be at least 64 bytes
+    let key = match env::var("SESSION_KEY") {
+        Ok(key) => Key::try_from(key.as_bytes())
+            .context("SESSION_KEY must be at least 64 bytes")
+            .unwrap(),
+        Err(_) => {
+            tracing::warn!("SESSION_KEY is not set, using a random key");
+            Key::generate()
+        }
+    };
+
+    // Browsers drop `Secure` cookies over plain HTTP, so allow turning it off for local development
+    let secure_cookies = env::var("COOKIE_SECURE").map_or(true, |secure| secure != "false");
 
     let app_state = AppState {
         store,
-        oauth_client,
+        accounts: AccountStore::default(),
+        providers: Arc::new(providers),
+        cipher: TokenCipher::new(&key),
+        key,
+        secure_cookies,
     };
 
-    let app = Router::new()
-        .route("/", get(index))
-        .route("/auth/github", get(auth_github))
-        .route("/auth/callback", get(auth_github_callback)) // redirect url
-        .route("/protected", get(protected))
-        .route("/logout", get(logout))
-        .with_state(app_state);
-
     let listener = TcpListener::bind("localhost:3000")
         .await
         .context("failed to bind TcpListener")
@@ -58,13 +84,28 @@ async fn main() {
 
     tracing::debug!("listening on {}", listener.local_addr().unwrap());
 
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(listener, app(app_state)).await.unwrap();
+}
+
+fn app(app_state: AppState) -> Router {
+    Router::new()
+        .route("/", get(index))
+        .route("/auth/{provider}", get(auth_provider))
+        .route("/auth/{provider}/callback", get(auth_callback)) // redirect url
+        .route("/protected", get(protected))
+        .route("/api/{provider}/user", get(provider_user))
+        .route("/logout", get(logout))
+        .with_state(app_state)
 }
 
 #[derive(Clone)]
 struct AppState {
     store: MemoryStore,
-    oauth_client: BasicClient,
+    accounts: AccountStore,
+    providers: Arc<Providers>,
+    cipher: TokenCipher,
+    key: Key,
+    secure_cookies: bool,
 }
 
 impl FromRef<AppState> for MemoryStore {
@@ -73,109 +114,177 @@ impl FromRef<AppState> for MemoryStore {
     }
 }
 
-impl FromRef<AppState> for BasicClient {
+impl FromRef<AppState> for AccountStore {
     fn from_ref(state: &AppState) -> Self {
-        state.oauth_client.clone()
+        state.accounts.clone()
     }
 }
 
-fn oauth_client() -> Result<BasicClient, AppError> {
-    let client_id: String = env::var("CLIENT_ID").context("Missing CLIENT_ID")?;
-    let client_secret = env::var("CLIENT_SECRET").context("Missing CLIENT_SECRET")?;
-    let redirect_uri = env::var("REDIRECT_URL").context("Missing REDIRECT_URL")?;
-
-    let auth_url = env::var("AUTH_URL").context("Missing AUTH_URL")?;
-
-    let token_url = env::var("TOKEN_URL").context("Missing TOKEN_URL")?;
-
-    let basic_client = BasicClient::new(
-        ClientId::new(client_id),
-        Some(ClientSecret::new(client_secret)),
-        AuthUrl::new(auth_url)?,
-        Some(TokenUrl::new(token_url)?),
-    )
-    .set_redirect_uri(RedirectUrl::new(redirect_uri.to_string())?);
+impl FromRef<AppState> for Arc<Providers> {
+    fn from_ref(state: &AppState) -> Self {
+        state.providers.clone()
+    }
+}
 
-    Ok(basic_client)
+impl FromRef<AppState> for TokenCipher {
+    fn from_ref(state: &AppState) -> Self {
+        state.cipher.clone()
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct User {
-    avatar_url: String,
-    bio: String,
-    created_at: String,
-    email: String,
-    followers: usize,
-    following: usize,
-    html_url: String,
-    name: String,
-    location: String,
-    url: String,
+impl FromRef<AppState> for Key {
+    fn from_ref(state: &AppState) -> Self {
+        state.key.clone()
+    }
 }
 
-async fn index(user: Option<User>) -> impl IntoResponse {
+async fn index(
+    State(providers): State<Arc<Providers>>,
+    user: Option<User>,
+) -> impl IntoResponse {
     match user {
-        Some(u) => Html(format!(
-            r#"
+        Some(u) => {
+            let identities = u
+                .identities
+                .iter()
+                .map(|i| format!("<li>{} ({})</li>", i.provider, i.name))
+                .collect::<String>();
+
+            let link = providers
+                .keys()
+                .filter(|name| !u.is_linked(name))
+                .map(|name| format!(r#"<li><a href="/auth/{name}">Link {name}</a></li>"#))
+                .collect::<String>();
+
+            Html(format!(
+                r#"
             <!DOCTYPE html>
             <html>
                 <body>
                     <h1>Hello, {}!</h1>
-                    <p>You're logged in!</p>
+                    <p>You're logged in with:</p>
+                    <ul>{identities}</ul>
+                    <ul>{link}</ul>
                     <p>Visit <a href="/protected">/protected</a> to access the protected route.</p>
                     <p>Or <a href="/logout">/logout</a> to log out.</p>
                 </body>
             </html>
         "#,
-            u.name
-        )),
-        None => Html(
-            r#"
+                u.name
+            ))
+        }
+        None => {
+            let login = providers
+                .keys()
+                .map(|name| format!(r#"<li><a href="/auth/{name}">/auth/{name}</a></li>"#))
+                .collect::<String>();
+
+            Html(format!(
+                r#"
             <!DOCTYPE html>
             <html>
                 <body>
                     <h1>You're not logged in.</h1>
-                    <p>Visit <a href="/auth/github">/auth/github</a> to do so.</p>
+                    <p>Visit one of these to do so:</p>
+                    <ul>{login}</ul>
                 </body>
             </html>
         "#
-            .to_string(),
-        ),
+            ))
+        }
     }
 }
 
-// Store the CSRF token in the session, then redirect user to the auth url
-async fn auth_github(
-    State(client): State<BasicClient>,
+// Store the CSRF token, PKCE verifier (and OIDC nonce) in the session, then redirect user to
+// the auth url. A user that is already logged in links the new identity to their account, and
+// stays logged in with their current session until the callback has linked it.
+async fn auth_provider(
+    Path(provider): Path<String>,
+    State(state): State<AppState>,
     State(store): State<MemoryStore>,
-) -> Result<impl IntoResponse, AppError> {
-    let (auth_url, csrf_token) = client
-        .authorize_url(CsrfToken::new_random)
-        .add_scope(Scope::new("user:email".to_string()))
-        .add_extra_param("prompt", "consent")
-        .url();
+    jar: PrivateCookieJar,
+) -> Result<Response, AppError> {
+    let Some(provider) = state.providers.get(&provider) else {
+        return Err(AuthError::UnknownProvider(provider).into());
+    };
 
-    let mut session = Session::new();
+    let mut logged_in = None;
+
+    if let Some(cookie) = jar.get(COOKIE_NAME) {
+        if let Some(current) = store
+            .load_session(cookie.value().to_string())
+            .await
+            .context("Failed to load session")?
+        {
+            if current.get::<u64>(ACCOUNT_ID).is_some() {
+                logged_in = Some(current);
+            } else {
+                // An unfinished login, replaced by the one below
+                store
+                    .destroy_session(current)
+                    .await
+                    .context("Failed to destroy session")?;
+            }
+        }
+    }
+
+    let auth = provider.authorize_url();
+
+    let linking = logged_in.is_some();
+    let mut session = logged_in.unwrap_or_else(Session::new);
+
+    session
+        .insert(PROVIDER, &provider.name)
+        .context("failed to insert provider into session")?;
 
     session
-        .insert(CSRF_TOKEN, &csrf_token)
+        .insert(CSRF_TOKEN, &auth.csrf_token)
         .context("failed to insert CSRF token into session")?;
 
+    session
+        .insert(PKCE_VERIFIER, &auth.pkce_verifier)
+        .context("failed to insert PKCE verifier into session")?;
+
+    if let Some(nonce) = &auth.nonce {
+        session
+            .insert(NONCE, nonce)
+            .context("failed to insert nonce into session")?;
+    }
+
+    if linking {
+        // The browser already has the cookie for this session
+        store
+            .store_session(session)
+            .await
+            .context("failed to store session")?;
+
+        return Ok(Redirect::to(auth.url.as_ref()).into_response());
+    }
+
+    let cookie = store_session(&store, session, state.secure_cookies).await?;
+
+    Ok((jar.add(cookie), Redirect::to(auth.url.as_ref())).into_response())
+}
+
+// Stores the session and returns the cookie pointing to it. The cookie is encrypted by the
+// `PrivateCookieJar` it's added to.
+async fn store_session(
+    store: &MemoryStore,
+    session: Session,
+    secure: bool,
+) -> Result<Cookie<'static>, AppError> {
     let cookie = store
         .store_session(session)
         .await
         .context("failed to store session")?
         .context("failed to retrieve session cookie")?;
 
-    let cookie = format!("{COOKIE_NAME}={cookie}; Path=/; Secure; HttpOnly; SameSite=Lax");
-    let mut headers = HeaderMap::new();
-
-    headers.insert(
-        header::SET_COOKIE,
-        cookie.parse().context("failed to parse cookie")?,
-    );
-
-    Ok((headers, Redirect::to(auth_url.as_ref())))
+    Ok(Cookie::build((COOKIE_NAME, cookie))
+        .path("/")
+        .secure(secure)
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .build())
 }
 
 #[derive(Debug, Deserialize)]
@@ -185,109 +294,147 @@ struct AuthRequest {
     state: String,
 }
 
+// What `/auth/{provider}` stored in the session for the callback
+struct LoginState {
+    provider: Option<String>,
+    pkce_verifier: Option<PkceCodeVerifier>,
+    nonce: Option<Nonce>,
+    // The account of a logged in user linking another identity, along with their tokens
+    account_id: Option<u64>,
+    tokens: SealedTokens,
+    session: Session,
+}
+
+// A login session is destroyed right away, while a logged in user linking an identity keeps
+// their session with the login state removed, so it can only be used once either way.
 async fn validate_csrf_token(
     query: &AuthRequest,
-    cookies: &headers::Cookie,
+    jar: &PrivateCookieJar,
     store: &MemoryStore,
-) -> Result<(), AppError> {
-    let cookie = cookies
-        .get(COOKIE_NAME)
-        .context("unexpected error getting cookie")?
-        .to_string();
+) -> Result<LoginState, AppError> {
+    let cookie = jar.get(COOKIE_NAME).ok_or(AuthError::MissingLoginSession)?;
 
-    let session = match store
-        .load_session(cookie)
+    let mut session = store
+        .load_session(cookie.value().to_string())
         .await
-        .context("Failed to load session")?
-    {
-        Some(session) => session,
-        None => return Err(anyhow!("Session not found").into()),
-    };
+        .map_err(AuthError::SessionStore)?
+        .ok_or(AuthError::MissingLoginSession)?;
 
     let stored_csrf_token = session
         .get::<CsrfToken>(CSRF_TOKEN)
-        .context("Failed to get CSRF token")?;
+        .ok_or(AuthError::MissingLoginSession)?;
+
+    // Read before the login state is removed, clones of a session share their data
+    let login = LoginState {
+        provider: session.get(PROVIDER),
+        pkce_verifier: session.get(PKCE_VERIFIER),
+        nonce: session.get(NONCE),
+        account_id: session.get(ACCOUNT_ID),
+        tokens: session.get(TOKENS).unwrap_or_default(),
+        session: session.clone(),
+    };
 
-    store
-        .destroy_session(session)
-        .await
-        .context("Failed to destroy session")?;
+    if login.account_id.is_some() {
+        for key in [PROVIDER, CSRF_TOKEN, PKCE_VERIFIER, NONCE] {
+            session.remove(key);
+        }
+
+        store
+            .store_session(session)
+            .await
+            .context("Failed to store session")?;
+    } else {
+        store
+            .destroy_session(session)
+            .await
+            .context("Failed to destroy session")?;
+    }
 
     // Verify CSRF token is the same as the one in the auth request
     if *stored_csrf_token.secret() != query.state {
-        return Err(anyhow!("CSRF token mismatch").into());
+        return Err(AuthError::CsrfMismatch.into());
     }
 
-    Ok(())
+    Ok(login)
 }
 
-async fn auth_github_callback(
+async fn auth_callback(
+    Path(provider): Path<String>,
     Query(query): Query<AuthRequest>,
+    State(state): State<AppState>,
+    State(accounts): State<AccountStore>,
     State(store): State<MemoryStore>,
-    State(client): State<BasicClient>,
-    TypedHeader(cookies): TypedHeader<headers::Cookie>,
+    jar: PrivateCookieJar,
 ) -> Result<impl IntoResponse, AppError> {
-    validate_csrf_token(&query, &cookies, &store).await?;
+    let Some(provider) = state.providers.get(&provider) else {
+        return Err(AuthError::UnknownProvider(provider).into());
+    };
 
-    let token = client
-        .exchange_code(AuthorizationCode::new(query.code.clone()))
-        .request_async(async_http_client)
-        .await
-        .context("Failed to exchange code for token")?;
+    let login = validate_csrf_token(&query, &jar, &store).await?;
 
-    let client = reqwest::Client::new();
+    // The callback must belong to the provider the login was started with
+    if login.provider.as_ref() != Some(&provider.name) {
+        return Err(AuthError::ProviderMismatch.into());
+    }
 
-    let response = client
-        .get("https://api.github.com/user")
-        .bearer_auth(token.access_token().secret())
-        .header("User-Agent", "axum-oauth-example")
-        .send()
-        .await
-        .context("Failed to send request to get user data from GitHub")?;
+    let pkce_verifier = login.pkce_verifier.context("Failed to get PKCE verifier")?;
 
-    if response.status().is_client_error() || response.status().is_server_error() {
-        let message = response
-            .text()
-            .await
-            .unwrap_or("Something went wrong".to_string());
-        return Err(anyhow!(message).into());
-    }
+    let (identity, tokens) = provider
+        .fetch_identity(query.code, pkce_verifier, login.nonce)
+        .await?;
+
+    tracing::debug!("Identity: {:#?}", identity);
+
+    let user = match login.account_id {
+        Some(account_id) => {
+            let user = accounts
+                .link(account_id, identity)
+                .map_err(AuthError::from)?;
 
-    let user = response.json::<User>().await.unwrap();
+            // Replaced by the new session below
+            store
+                .destroy_session(login.session)
+                .await
+                .context("Failed to destroy session")?;
 
-    tracing::debug!("User data: {:#?}", user);
+            user
+        }
+        None => accounts.login(identity),
+    };
 
+    // The previous session is gone, so logging in always hands out a new session ID and a
+    // session planted before the login can't be taken over (session fixation)
     let mut session = Session::new();
 
     session
-        .insert("user", &user)
-        .context("Failed to insert user into session")?;
-
-    let cookie = store
-        .store_session(session)
-        .await
-        .context("Failed to store session")?
-        .context("Failed to retrieve session cookie")?;
+        .insert(ACCOUNT_ID, user.id)
+        .context("Failed to insert account into session")?;
 
-    let cookie = format!("{COOKIE_NAME}={cookie}; Path=/; Secure; HttpOnly; SameSite=Lax");
-    let mut headers = HeaderMap::new();
+    // Only the sealed tokens end up in the session store
+    let mut sealed_tokens = login.tokens;
 
-    headers.insert(
-        header::SET_COOKIE,
-        cookie.parse().context("failed to parse cookie")?,
+    sealed_tokens.insert(
+        provider.name.clone(),
+        state.cipher.seal(&provider.name, &tokens)?,
     );
 
-    Ok((headers, Redirect::to("/")))
+    session
+        .insert(TOKENS, &sealed_tokens)
+        .context("Failed to insert tokens into session")?;
+
+    let cookie = store_session(&store, session, state.secure_cookies).await?;
+
+    Ok((jar.add(cookie), Redirect::to("/")))
 }
 
 async fn logout(
     State(store): State<MemoryStore>,
-    TypedHeader(cookies): TypedHeader<headers::Cookie>,
+    jar: PrivateCookieJar,
 ) -> Result<Response, AppError> {
-    let cookie = cookies.get(COOKIE_NAME).context("Missing cookie")?;
+    let cookie = jar.get(COOKIE_NAME).context("Missing cookie")?;
 
     let session = match store
-        .load_session(cookie.to_string())
+        .load_session(cookie.value().to_string())
         .await
         .context("Failed to load session")?
     {
@@ -301,19 +448,9 @@ async fn logout(
         .context("Failed to destroy session")?;
 
     // Remove the cookie from the response
-    let cookie = format!("{COOKIE_NAME}=; Expires=Thu, 01 Jan 1970 00:00:00 GMT; Path=/");
-    let mut headers = HeaderMap::new();
-
-    headers.insert(
-        header::SET_COOKIE,
-        cookie.parse().context("failed to parse cookie")?,
-    );
-
-    let mut response = Redirect::to("/").into_response();
-
-    response.headers_mut().extend(headers);
+    let jar = jar.remove(Cookie::build(COOKIE_NAME).path("/"));
 
-    Ok(response)
+    Ok((jar, Redirect::to("/")).into_response())
 }
 
 async fn protected(user: User) -> impl IntoResponse {
@@ -323,43 +460,131 @@ async fn protected(user: User) -> impl IntoResponse {
     )
 }
 
-struct AuthRedirect;
+// Calls the provider's user info endpoint on behalf of the logged in user
+async fn provider_user(client: AuthorizedClient) -> Result<impl IntoResponse, AppError> {
+    let url = client
+        .provider
+        .user_info_url()
+        .context("Provider has no user info endpoint")?;
 
-impl IntoResponse for AuthRedirect {
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .context("Failed to send request to provider")?;
+
+    let status = response.status();
+
+    let user_info = response
+        .json::<serde_json::Value>()
+        .await
+        .context("Failed to read provider response")?;
+
+    Ok((status, Json(user_info)))
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    // No valid session cookie, the user has to log in first
+    Unauthenticated,
+    UnknownProvider(String),
+    // The callback was hit without the session created by `/auth/{provider}`
+    MissingLoginSession,
+    CsrfMismatch,
+    // The callback is for another provider than the one the login was started with
+    ProviderMismatch,
+    // The account has no identity linked for the provider
+    MissingProviderToken,
+    // The identity to link already belongs to another account
+    IdentityTaken,
+    // The provider token expired and could not be refreshed, the user has to log in again
+    TokenExpired,
+    // The provider's user data could not be fetched or mapped
+    Provider(anyhow::Error),
+    SessionStore(async_session::Error),
+}
+
+impl IntoResponse for AuthError {
     fn into_response(self) -> Response {
-        Redirect::temporary("/auth/github").into_response()
+        let (status_code, message) = match self {
+            // The index page lists the configured login providers
+            AuthError::Unauthenticated => return Redirect::temporary("/").into_response(),
+            AuthError::UnknownProvider(provider) => {
+                return (
+                    StatusCode::NOT_FOUND,
+                    format!("Unknown provider {provider}"),
+                )
+                    .into_response()
+            }
+            AuthError::MissingLoginSession => (StatusCode::BAD_REQUEST, "Missing login session"),
+            AuthError::CsrfMismatch => (StatusCode::BAD_REQUEST, "CSRF token mismatch"),
+            AuthError::ProviderMismatch => (StatusCode::BAD_REQUEST, "Provider mismatch"),
+            AuthError::MissingProviderToken => {
+                (StatusCode::FORBIDDEN, "No token for this provider")
+            }
+            AuthError::IdentityTaken => (
+                StatusCode::CONFLICT,
+                "This identity is already linked to another account",
+            ),
+            AuthError::TokenExpired => (StatusCode::UNAUTHORIZED, "Provider token expired"),
+            AuthError::Provider(e) => {
+                tracing::error!("Provider error: {:#}", e);
+                (StatusCode::BAD_GATEWAY, "Failed to get user data from provider")
+            }
+            AuthError::SessionStore(e) => {
+                tracing::error!("Session store error: {:#}", e);
+                (StatusCode::INTERNAL_SERVER_ERROR, "Session store error")
+            }
+        };
+
+        tracing::error!("AuthError: {}", message);
+
+        (status_code, message).into_response()
+    }
+}
+
+impl From<LinkError> for AuthError {
+    fn from(err: LinkError) -> Self {
+        match err {
+            LinkError::AlreadyLinked => AuthError::IdentityTaken,
+            // The account was removed while the user was away at the provider
+            LinkError::AccountNotFound => AuthError::Unauthenticated,
+        }
     }
 }
 
 impl<S> FromRequestParts<S> for User
 where
     MemoryStore: FromRef<S>,
+    AccountStore: FromRef<S>,
+    Key: FromRef<S>,
     S: Send + Sync,
 {
-    type Rejection = AuthRedirect;
+    type Rejection = AuthError;
     async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
         let store = MemoryStore::from_ref(state);
+        let accounts = AccountStore::from_ref(state);
 
-        let cookies = parts
-            .extract::<TypedHeader<headers::Cookie>>()
+        // Cookies that fail to decrypt are left out of the jar, just like missing ones
+        let jar = PrivateCookieJar::<Key>::from_request_parts(parts, state)
             .await
-            .map_err(|e| match *e.name() {
-                header::COOKIE => match e.reason() {
-                    TypedHeaderRejectionReason::Missing => AuthRedirect,
-                    _ => panic!("Unexpected rejection getting Cookie headers: {:#?}", e),
-                },
-                _ => panic!("Unexpected rejection while extracting cookies: {:#?}", e),
-            })?;
+            .unwrap_or_else(|e| match e {});
 
-        let session_cookie = cookies.get(COOKIE_NAME).ok_or(AuthRedirect)?;
+        let session_cookie = jar.get(COOKIE_NAME).ok_or(AuthError::Unauthenticated)?;
 
         let session = store
-            .load_session(session_cookie.to_string())
+            .load_session(session_cookie.value().to_string())
             .await
-            .unwrap()
-            .ok_or(AuthRedirect)?;
+            .map_err(AuthError::SessionStore)?
+            .ok_or(AuthError::Unauthenticated)?;
+
+        let account_id = session
+            .get::<u64>(ACCOUNT_ID)
+            .ok_or(AuthError::Unauthenticated)?;
 
-        let user = session.get::<User>("user").ok_or(AuthRedirect)?;
+        let user = accounts
+            .get(account_id)
+            .ok_or(AuthError::Unauthenticated)?;
 
         Ok(user)
     }
@@ -368,9 +593,11 @@ where
 impl<S> OptionalFromRequestParts<S> for User
 where
     MemoryStore: FromRef<S>,
+    AccountStore: FromRef<S>,
+    Key: FromRef<S>,
     S: Send + Sync,
 {
-    type Rejection = Infallible;
+    type Rejection = AuthError;
 
     async fn from_request_parts(
         parts: &mut Parts,
@@ -378,18 +605,136 @@ where
     ) -> Result<Option<Self>, Self::Rejection> {
         match <User as FromRequestParts<S>>::from_request_parts(parts, state).await {
             Ok(user) => Ok(Some(user)),
-            Err(AuthRedirect) => Ok(None),
+            Err(AuthError::Unauthenticated) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// HTTP client authorized with the logged in user's token for the `{provider}` in the path.
+///
+/// An expired token is refreshed (and stored back in the session) before the client is handed
+/// out.
+pub struct AuthorizedClient {
+    pub provider: Provider,
+    access_token: AccessToken,
+}
+
+impl AuthorizedClient {
+    pub fn get(&self, url: &str) -> reqwest::RequestBuilder {
+        self.request(reqwest::Method::GET, url)
+    }
+
+    pub fn request(&self, method: reqwest::Method, url: &str) -> reqwest::RequestBuilder {
+        self.provider
+            .http()
+            .request(method, url)
+            .bearer_auth(self.access_token.secret())
+            .header("User-Agent", "axum-oauth-example")
+    }
+}
+
+impl<S> FromRequestParts<S> for AuthorizedClient
+where
+    MemoryStore: FromRef<S>,
+    Arc<Providers>: FromRef<S>,
+    TokenCipher: FromRef<S>,
+    Key: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let store = MemoryStore::from_ref(state);
+        let cipher = TokenCipher::from_ref(state);
+
+        let Path(params) = parts
+            .extract::<Path<HashMap<String, String>>>()
+            .await
+            .context("Failed to extract path params")?;
+
+        let name = params
+            .get("provider")
+            .context("Missing provider in path")?;
+
+        let provider = Arc::<Providers>::from_ref(state)
+            .get(name)
+            .with_context(|| format!("Unknown provider {name}"))?
+            .clone();
+
+        let jar = PrivateCookieJar::<Key>::from_request_parts(parts, state)
+            .await
+            .unwrap_or_else(|e| match e {});
+
+        let cookie = jar.get(COOKIE_NAME).ok_or(AuthError::Unauthenticated)?;
+
+        let mut session = store
+            .load_session(cookie.value().to_string())
+            .await
+            .map_err(AuthError::SessionStore)?
+            .ok_or(AuthError::Unauthenticated)?;
+
+        let mut sealed_tokens = session
+            .get::<SealedTokens>(TOKENS)
+            .unwrap_or_default();
+
+        let sealed = sealed_tokens
+            .get(name)
+            .ok_or(AuthError::MissingProviderToken)?;
+
+        let mut tokens = cipher.open(name, sealed)?;
+
+        if tokens.is_expired() {
+            let refresh_token = tokens
+                .refresh_token
+                .as_ref()
+                .ok_or(AuthError::TokenExpired)?;
+
+            tokens = provider.refresh(refresh_token).await.map_err(|e| {
+                tracing::warn!("Failed to refresh {name} token: {:?}", e);
+                AuthError::TokenExpired
+            })?;
+
+            sealed_tokens.insert(name.clone(), cipher.seal(name, &tokens)?);
+
+            session
+                .insert(TOKENS, &sealed_tokens)
+                .context("Failed to insert tokens into session")?;
+
+            store
+                .store_session(session)
+                .await
+                .map_err(AuthError::SessionStore)?;
         }
+
+        Ok(AuthorizedClient {
+            provider,
+            access_token: tokens.access_token,
+        })
     }
 }
 
 #[derive(Debug)]
-struct AppError(anyhow::Error);
+pub enum AppError {
+    Auth(AuthError),
+    Internal(anyhow::Error),
+}
 
 impl IntoResponse for AppError {
     fn into_response(self) -> axum::response::Response {
-        tracing::error!("App error: {:#}", self.0);
-        (StatusCode::INTERNAL_SERVER_ERROR, self.0.to_string()).into_response()
+        match self {
+            AppError::Auth(e) => e.into_response(),
+            AppError::Internal(e) => {
+                tracing::error!("App error: {:#}", e);
+                (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+            }
+        }
+    }
+}
+
+impl From<AuthError> for AppError {
+    fn from(value: AuthError) -> Self {
+        Self::Auth(value)
     }
 }
 
@@ -398,6 +743,503 @@ where
     E: Into<anyhow::Error>,
 {
     fn from(value: E) -> Self {
-        Self(value.into())
+        Self::Internal(value.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::header;
+    use reqwest::redirect::Policy;
+
+    use super::*;
+    use mock_provider::{IdToken, MockProvider};
+    use providers::OAuthConfig;
+
+    // Serves the app on an ephemeral port with a "github" (plain OAuth2) and an "oidc" provider,
+    // both backed by the mock authorization server
+    async fn spawn_app() -> (String, MockProvider) {
+        let mock = mock_provider::spawn().await;
+        let provider_url = &mock.url;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        let github = Provider::oauth(
+            "github",
+            OAuthConfig {
+                client_id: "client-id".to_string(),
+                client_secret: "client-secret".to_string(),
+                auth_url: format!("{provider_url}/authorize"),
+                token_url: format!("{provider_url}/token"),
+                user_info_url: format!("{provider_url}/user"),
+                redirect_url: format!("{url}/auth/github/callback"),
+                scopes: vec!["user:email".to_string()],
+            },
+            reqwest::Client::new(),
+        )
+        .unwrap();
+
+        let oidc = Provider::oidc(
+            "oidc",
+            provider_url.clone(),
+            mock_provider::CLIENT_ID.to_string(),
+            "client-secret".to_string(),
+            format!("{url}/auth/oidc/callback"),
+            reqwest::Client::new(),
+        )
+        .await
+        .unwrap();
+
+        let key = Key::generate();
+
+        let app_state = AppState {
+            store: MemoryStore::new(),
+            accounts: AccountStore::default(),
+            providers: Arc::new(Providers::from([
+                ("github".to_string(), github),
+                ("oidc".to_string(), oidc),
+            ])),
+            cipher: TokenCipher::new(&key),
+            key,
+            secure_cookies: false,
+        };
+
+        tokio::spawn(async move { axum::serve(listener, app(app_state)).await.unwrap() });
+
+        (url, mock)
+    }
+
+    fn client() -> reqwest::Client {
+        reqwest::Client::builder()
+            .redirect(Policy::none())
+            .build()
+            .unwrap()
+    }
+
+    fn location(response: &reqwest::Response) -> String {
+        response.headers()[header::LOCATION]
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    // The `SESSION=...` pair of the `Set-Cookie` header, ready to be sent back
+    fn session_cookie(response: &reqwest::Response) -> String {
+        response.headers()[header::SET_COOKIE]
+            .to_str()
+            .unwrap()
+            .split(';')
+            .next()
+            .unwrap()
+            .to_string()
+    }
+
+    // Starts the login with `provider` and lets the mock provider approve it, returning the
+    // callback url and the cookie of the login session
+    async fn authorize(client: &reqwest::Client, url: &str, provider: &str) -> (String, String) {
+        let response = client
+            .get(format!("{url}/auth/{provider}"))
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+        let cookie = session_cookie(&response);
+        let authorize_url = location(&response);
+
+        assert!(authorize_url.contains("code_challenge_method=S256"));
+
+        let response = client.get(authorize_url).send().await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+        (location(&response), cookie)
+    }
+
+    // Logs in with `provider`, returning the session cookie
+    async fn login(client: &reqwest::Client, url: &str, provider: &str) -> String {
+        let (callback_url, login_cookie) = authorize(client, url, provider).await;
+
+        let response = client
+            .get(callback_url)
+            .header(header::COOKIE, login_cookie)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+        session_cookie(&response)
+    }
+
+    // Links `provider` to the account logged in with `cookie`, returning the callback response
+    async fn link(
+        client: &reqwest::Client,
+        url: &str,
+        provider: &str,
+        cookie: &str,
+    ) -> reqwest::Response {
+        let response = client
+            .get(format!("{url}/auth/{provider}"))
+            .header(header::COOKIE, cookie)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+        // The current session carries the login state, the user stays logged in meanwhile
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+
+        let authorize_url = location(&response);
+
+        let response = client
+            .get(format!("{url}/protected"))
+            .header(header::COOKIE, cookie)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = client.get(authorize_url).send().await.unwrap();
+
+        client
+            .get(location(&response))
+            .header(header::COOKIE, cookie)
+            .send()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_login_flow() {
+        let (url, _) = spawn_app().await;
+        let client = client();
+
+        let response = client
+            .get(format!("{url}/protected"))
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+
+        let (callback_url, login_cookie) = authorize(&client, &url, "github").await;
+
+        assert!(callback_url.starts_with(&format!("{url}/auth/github/callback")));
+
+        let response = client
+            .get(callback_url)
+            .header(header::COOKIE, &login_cookie)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(location(&response), "/");
+        assert!(!response.headers()[header::SET_COOKIE]
+            .to_str()
+            .unwrap()
+            .contains("Secure"));
+
+        let cookie = session_cookie(&response);
+
+        // Logging in rotates the session, the login session can't be used anymore
+        assert_ne!(cookie, login_cookie);
+
+        let response = client
+            .get(format!("{url}/protected"))
+            .header(header::COOKIE, &login_cookie)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+
+        // A tampered cookie fails to decrypt and is ignored
+        let middle = cookie.len() / 2;
+        let flipped = if &cookie[middle..=middle] == "A" { "B" } else { "A" };
+        let tampered = format!("{}{flipped}{}", &cookie[..middle], &cookie[middle + 1..]);
+
+        let response = client
+            .get(format!("{url}/protected"))
+            .header(header::COOKIE, tampered)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+
+        let response = client
+            .get(format!("{url}/protected"))
+            .header(header::COOKIE, &cookie)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.text().await.unwrap().contains("The Octocat"));
+
+        let response = client
+            .get(format!("{url}/logout"))
+            .header(header::COOKIE, &cookie)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert!(response.headers()[header::SET_COOKIE]
+            .to_str()
+            .unwrap()
+            .contains("Max-Age=0"));
+
+        // The session is gone, even if the browser kept the cookie
+        let response = client
+            .get(format!("{url}/protected"))
+            .header(header::COOKIE, &cookie)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+    }
+
+    #[tokio::test]
+    async fn test_provider_api_refreshes_token() {
+        let (url, mock) = spawn_app().await;
+        let client = client();
+
+        let (callback_url, login_cookie) = authorize(&client, &url, "github").await;
+
+        let response = client
+            .get(callback_url)
+            .header(header::COOKIE, &login_cookie)
+            .send()
+            .await
+            .unwrap();
+
+        let cookie = session_cookie(&response);
+
+        // The mock's tokens are always stale, and its refresh tokens only work once, so the
+        // second call only succeeds if the refreshed tokens were stored back in the session
+        for refreshes in 1..=2 {
+            let response = client
+                .get(format!("{url}/api/github/user"))
+                .header(header::COOKIE, &cookie)
+                .send()
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let user = response.json::<serde_json::Value>().await.unwrap();
+
+            assert_eq!(user["login"], "octocat");
+            assert_eq!(mock.refreshes(), refreshes);
+        }
+
+        let response = client
+            .get(format!("{url}/api/github/user"))
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+    }
+
+    #[tokio::test]
+    async fn test_callback_csrf_mismatch() {
+        let (url, _) = spawn_app().await;
+        let client = client();
+
+        let (callback_url, login_cookie) = authorize(&client, &url, "github").await;
+
+        let (callback_url, _) = callback_url.split_once("&state=").unwrap();
+
+        let response = client
+            .get(format!("{callback_url}&state=forged"))
+            .header(header::COOKIE, login_cookie)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.text().await.unwrap(), "CSRF token mismatch");
+    }
+
+    #[tokio::test]
+    async fn test_callback_provider_mismatch() {
+        let (url, _) = spawn_app().await;
+        let client = client();
+
+        let (callback_url, login_cookie) = authorize(&client, &url, "github").await;
+
+        // A valid callback of the GitHub login, sent to the OIDC provider's callback
+        let callback_url = callback_url.replace("/auth/github/", "/auth/oidc/");
+
+        let response = client
+            .get(callback_url)
+            .header(header::COOKIE, login_cookie)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.text().await.unwrap(), "Provider mismatch");
+    }
+
+    #[tokio::test]
+    async fn test_unknown_provider() {
+        let (url, _) = spawn_app().await;
+        let client = client();
+
+        for path in [
+            "/auth/gitlab",
+            "/auth/gitlab/callback?code=code&state=state",
+        ] {
+            let response = client.get(format!("{url}{path}")).send().await.unwrap();
+
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{path}");
+            assert_eq!(response.text().await.unwrap(), "Unknown provider gitlab");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_callback_missing_cookie() {
+        let (url, _) = spawn_app().await;
+        let client = client();
+
+        let (callback_url, _) = authorize(&client, &url, "github").await;
+
+        let response = client.get(callback_url).send().await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.text().await.unwrap(), "Missing login session");
+    }
+
+    #[tokio::test]
+    async fn test_oidc_login() {
+        let (url, _) = spawn_app().await;
+        let client = client();
+
+        let (callback_url, login_cookie) = authorize(&client, &url, "oidc").await;
+
+        assert!(callback_url.starts_with(&format!("{url}/auth/oidc/callback")));
+
+        let response = client
+            .get(callback_url)
+            .header(header::COOKIE, login_cookie)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+        let response = client
+            .get(format!("{url}/protected"))
+            .header(header::COOKIE, session_cookie(&response))
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.text().await.unwrap().contains("Jane Doe"));
+    }
+
+    #[tokio::test]
+    async fn test_oidc_rejects_invalid_id_token() {
+        let (url, mock) = spawn_app().await;
+        let client = client();
+
+        for id_token in [IdToken::WrongNonce, IdToken::BadSignature] {
+            mock.set_id_token(id_token);
+
+            let (callback_url, login_cookie) = authorize(&client, &url, "oidc").await;
+
+            let response = client
+                .get(callback_url)
+                .header(header::COOKIE, login_cookie)
+                .send()
+                .await
+                .unwrap();
+
+            // The provider misbehaved, not the server
+            assert_eq!(response.status(), StatusCode::BAD_GATEWAY, "{id_token:?}");
+            assert!(response.headers().get(header::SET_COOKIE).is_none());
+            assert_eq!(
+                response.text().await.unwrap(),
+                "Failed to get user data from provider",
+                "{id_token:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_link_identity() {
+        let (url, _) = spawn_app().await;
+        let client = client();
+
+        let cookie = login(&client, &url, "github").await;
+
+        let response = link(&client, &url, "oidc", &cookie).await;
+
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+        let linked_cookie = session_cookie(&response);
+
+        assert_ne!(linked_cookie, cookie);
+
+        let response = client
+            .get(format!("{url}/"))
+            .header(header::COOKIE, &linked_cookie)
+            .send()
+            .await
+            .unwrap();
+
+        let index = response.text().await.unwrap();
+
+        assert!(index.contains("Hello, The Octocat!"));
+        assert!(index.contains("<li>github (The Octocat)</li>"));
+        assert!(index.contains("<li>oidc (Jane Doe)</li>"));
+
+        // Logging in with the linked identity leads to the same account
+        let cookie = login(&client, &url, "oidc").await;
+
+        let response = client
+            .get(format!("{url}/protected"))
+            .header(header::COOKIE, &cookie)
+            .send()
+            .await
+            .unwrap();
+
+        assert!(response.text().await.unwrap().contains("The Octocat"));
+    }
+
+    #[tokio::test]
+    async fn test_link_identity_of_another_account() {
+        let (url, _) = spawn_app().await;
+        let client = client();
+
+        // The mock provider always returns the same GitHub user
+        login(&client, &url, "github").await;
+        let cookie = login(&client, &url, "oidc").await;
+
+        let response = link(&client, &url, "github", &cookie).await;
+
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        // The user is still logged in to their own account
+        let response = client
+            .get(format!("{url}/protected"))
+            .header(header::COOKIE, &cookie)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.text().await.unwrap().contains("Jane Doe"));
     }
 }
@@ -0,0 +1,331 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Form, Json, Router,
+};
+use axum_extra::{
+    headers::{authorization::Bearer, Authorization},
+    TypedHeader,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use oauth2::{url::Url, AccessToken};
+use openidconnect::{
+    core::{
+        CoreIdToken, CoreIdTokenClaims, CoreJsonWebKeySet, CoreJwsSigningAlgorithm,
+        CoreRsaPrivateSigningKey,
+    },
+    JsonWebKeyId, PrivateSigningKey,
+};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tokio::net::TcpListener;
+
+// Signs the mock's id_tokens, only ever used in tests
+const SIGNING_KEY: &str = include_str!("certs/mock_provider_key.pem");
+const KEY_ID: &str = "mock-key";
+
+/// Client id the mock issues id_tokens for.
+pub const CLIENT_ID: &str = "client-id";
+
+/// In-process OAuth2 authorization server and OpenID Connect provider for the tests.
+///
+/// `/authorize` approves every request and redirects straight back with a code, `/token`
+/// trades the code (checking the PKCE verifier) or a refresh token for new tokens and `/user`
+/// returns a GitHub-like profile for the access token. Logins with the `openid` scope also get
+/// an RS256 signed id_token, verifiable with the keys from the discovery document.
+#[derive(Clone, Default)]
+pub struct MockProvider {
+    pub url: String,
+    next_id: Arc<AtomicU64>,
+    codes: Arc<Mutex<HashMap<String, PendingCode>>>,
+    tokens: Arc<Mutex<HashSet<String>>>,
+    refresh_tokens: Arc<Mutex<HashSet<String>>>,
+    refreshes: Arc<AtomicU64>,
+    id_token: Arc<Mutex<IdToken>>,
+}
+
+/// The kind of id_token the mock hands out, broken ones should be rejected by the app.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IdToken {
+    #[default]
+    Valid,
+    WrongNonce,
+    BadSignature,
+}
+
+struct PendingCode {
+    redirect_uri: String,
+    code_challenge: Option<String>,
+    // Only set for OpenID Connect logins
+    nonce: Option<String>,
+}
+
+/// Serves the mock provider on an ephemeral port.
+pub async fn spawn() -> MockProvider {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+    let provider = MockProvider {
+        url: format!("http://{}", listener.local_addr().unwrap()),
+        ..Default::default()
+    };
+
+    let app = Router::new()
+        .route("/.well-known/openid-configuration", get(discovery))
+        .route("/jwks", get(jwks))
+        .route("/authorize", get(authorize))
+        .route("/token", post(token))
+        .route("/user", get(user))
+        .with_state(provider.clone());
+
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+    provider
+}
+
+impl MockProvider {
+    /// Number of successful refresh token grants.
+    pub fn refreshes(&self) -> u64 {
+        self.refreshes.load(Ordering::Relaxed)
+    }
+
+    /// Sets the kind of id_token handed out by the following logins.
+    pub fn set_id_token(&self, id_token: IdToken) {
+        *self.id_token.lock().unwrap() = id_token;
+    }
+
+    fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn id_token(&self, nonce: &str, access_token: &str) -> String {
+        let kind = *self.id_token.lock().unwrap();
+
+        let nonce = match kind {
+            IdToken::WrongNonce => "some-other-nonce",
+            _ => nonce,
+        };
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let claims: CoreIdTokenClaims = serde_json::from_value(json!({
+            "iss": self.url,
+            "aud": [CLIENT_ID],
+            "exp": now + 300,
+            "iat": now,
+            "sub": "oidc-user-1",
+            "nonce": nonce,
+            "name": "Jane Doe",
+            "email": "jane@example.com",
+        }))
+        .unwrap();
+
+        let id_token = CoreIdToken::new(
+            claims,
+            &signing_key(),
+            CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256,
+            Some(&AccessToken::new(access_token.to_string())),
+            None,
+        )
+        .unwrap()
+        .to_string();
+
+        if kind != IdToken::BadSignature {
+            return id_token;
+        }
+
+        // Flip a bit of the signature, the header and claims stay intact
+        let (signed, signature) = id_token.rsplit_once('.').unwrap();
+        let mut signature = URL_SAFE_NO_PAD.decode(signature).unwrap();
+        signature[0] ^= 1;
+
+        format!("{signed}.{}", URL_SAFE_NO_PAD.encode(signature))
+    }
+}
+
+fn signing_key() -> CoreRsaPrivateSigningKey {
+    CoreRsaPrivateSigningKey::from_pem(SIGNING_KEY, Some(JsonWebKeyId::new(KEY_ID.to_string())))
+        .unwrap()
+}
+
+async fn discovery(State(provider): State<MockProvider>) -> Json<serde_json::Value> {
+    let url = &provider.url;
+
+    Json(json!({
+        "issuer": url,
+        "authorization_endpoint": format!("{url}/authorize"),
+        "token_endpoint": format!("{url}/token"),
+        "userinfo_endpoint": format!("{url}/user"),
+        "jwks_uri": format!("{url}/jwks"),
+        "response_types_supported": ["code"],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": ["RS256"],
+    }))
+}
+
+async fn jwks() -> Json<CoreJsonWebKeySet> {
+    Json(CoreJsonWebKeySet::new(vec![
+        signing_key().as_verification_key()
+    ]))
+}
+
+#[derive(Debug, Deserialize)]
+struct AuthorizeParams {
+    redirect_uri: String,
+    state: String,
+    code_challenge: Option<String>,
+    scope: Option<String>,
+    nonce: Option<String>,
+}
+
+async fn authorize(
+    State(provider): State<MockProvider>,
+    Query(params): Query<AuthorizeParams>,
+) -> Redirect {
+    let code = format!("code-{}", provider.next_id());
+
+    provider.codes.lock().unwrap().insert(
+        code.clone(),
+        PendingCode {
+            redirect_uri: params.redirect_uri.clone(),
+            code_challenge: params.code_challenge,
+            nonce: params
+                .scope
+                .is_some_and(|scope| scope.split(' ').any(|scope| scope == "openid"))
+                .then(|| params.nonce.unwrap_or_default()),
+        },
+    );
+
+    let mut redirect = Url::parse(&params.redirect_uri).unwrap();
+
+    redirect
+        .query_pairs_mut()
+        .append_pair("code", &code)
+        .append_pair("state", &params.state);
+
+    Redirect::to(redirect.as_str())
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "grant_type", rename_all = "snake_case")]
+enum TokenParams {
+    AuthorizationCode {
+        code: String,
+        redirect_uri: String,
+        code_verifier: Option<String>,
+    },
+    RefreshToken {
+        refresh_token: String,
+    },
+}
+
+async fn token(State(provider): State<MockProvider>, Form(params): Form<TokenParams>) -> Response {
+    let nonce = match params {
+        TokenParams::AuthorizationCode {
+            code,
+            redirect_uri,
+            code_verifier,
+        } => {
+            let Some(pending) = provider.codes.lock().unwrap().remove(&code) else {
+                return invalid_grant();
+            };
+
+            if pending.redirect_uri != redirect_uri {
+                return invalid_grant();
+            }
+
+            // S256: BASE64URL(SHA256(code_verifier)) must match the challenge from `/authorize`
+            let challenge = code_verifier
+                .map(|verifier| URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes())));
+
+            if pending.code_challenge != challenge {
+                return invalid_grant();
+            }
+
+            pending.nonce
+        }
+        TokenParams::RefreshToken { refresh_token } => {
+            // Refresh tokens are rotated, each one can only be used once
+            if !provider
+                .refresh_tokens
+                .lock()
+                .unwrap()
+                .remove(&refresh_token)
+            {
+                return invalid_grant();
+            }
+
+            provider.refreshes.fetch_add(1, Ordering::Relaxed);
+
+            None
+        }
+    };
+
+    let access_token = format!("token-{}", provider.next_id());
+    let refresh_token = format!("refresh-{}", provider.next_id());
+
+    provider
+        .tokens
+        .lock()
+        .unwrap()
+        .insert(access_token.clone());
+
+    provider
+        .refresh_tokens
+        .lock()
+        .unwrap()
+        .insert(refresh_token.clone());
+
+    // Expires well within the app's refresh leeway, so every API call refreshes the token first
+    let mut response = json!({
+        "access_token": access_token,
+        "refresh_token": refresh_token,
+        "token_type": "bearer",
+        "expires_in": 1,
+    });
+
+    if let Some(nonce) = nonce {
+        response["id_token"] = json!(provider.id_token(&nonce, &access_token));
+    }
+
+    Json(response).into_response()
+}
+
+fn invalid_grant() -> Response {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(json!({ "error": "invalid_grant" })),
+    )
+        .into_response()
+}
+
+async fn user(
+    State(provider): State<MockProvider>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+) -> Response {
+    if !provider.tokens.lock().unwrap().contains(bearer.token()) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    Json(json!({
+        "id": 42,
+        "login": "octocat",
+        "name": "The Octocat",
+        "email": "octocat@example.com",
+        "avatar_url": "https://example.com/octocat.png",
+    }))
+    .into_response()
+}
@@ -0,0 +1,119 @@
+use anyhow::Context;
+use openidconnect::{
+    core::{CoreAuthenticationFlow, CoreClient, CoreIdTokenClaims, CoreProviderMetadata},
+    url::Url,
+    AuthorizationCode, ClientId, ClientSecret, CsrfToken, IssuerUrl, Nonce, PkceCodeChallenge,
+    PkceCodeVerifier, RedirectUrl, Scope, TokenResponse,
+};
+
+use crate::{
+    accounts::Identity, http_client::async_http_client, tokens::ProviderTokens, AppError, AuthError,
+};
+
+/// Builds an OpenID Connect client for the issuer, along with its user info endpoint if it has
+/// one.
+///
+/// The endpoints and signing keys are discovered from the issuer's
+/// `/.well-known/openid-configuration` document.
+pub async fn oidc_client(
+    http: &reqwest::Client,
+    issuer_url: String,
+    client_id: String,
+    client_secret: String,
+    redirect_url: RedirectUrl,
+) -> anyhow::Result<(CoreClient, Option<String>)> {
+    let provider_metadata = CoreProviderMetadata::discover_async(
+        IssuerUrl::new(issuer_url)?,
+        |request| async_http_client(http, request),
+    )
+    .await
+    .context("Failed to discover OpenID Connect provider")?;
+
+    let user_info_url = provider_metadata
+        .userinfo_endpoint()
+        .map(|url| url.url().to_string());
+
+    let client = CoreClient::from_provider_metadata(
+        provider_metadata,
+        ClientId::new(client_id),
+        Some(ClientSecret::new(client_secret)),
+    )
+    .set_redirect_uri(redirect_url);
+
+    Ok((client, user_info_url))
+}
+
+pub fn authorize_url(
+    client: &CoreClient,
+    pkce_challenge: PkceCodeChallenge,
+) -> (Url, CsrfToken, Nonce) {
+    client
+        .authorize_url(
+            CoreAuthenticationFlow::AuthorizationCode,
+            CsrfToken::new_random,
+            Nonce::new_random,
+        )
+        .add_scope(Scope::new("email".to_string()))
+        .add_scope(Scope::new("profile".to_string()))
+        .set_pkce_challenge(pkce_challenge)
+        .url()
+}
+
+/// Exchanges the authorization code and maps the verified `id_token` claims onto an
+/// [`Identity`], returning the provider tokens along with it.
+pub async fn fetch_identity(
+    provider: &str,
+    client: &CoreClient,
+    http: &reqwest::Client,
+    code: String,
+    pkce_verifier: PkceCodeVerifier,
+    nonce: &Nonce,
+) -> Result<(Identity, ProviderTokens), AppError> {
+    let token = client
+        .exchange_code(AuthorizationCode::new(code))
+        .set_pkce_verifier(pkce_verifier)
+        .request_async(|request| async_http_client(http, request))
+        .await
+        .context("Failed to exchange code for token")?;
+
+    let id_token = token
+        .id_token()
+        .context("Provider did not return an ID token")
+        .map_err(AuthError::Provider)?;
+
+    // Checks the signature against the discovered JWKS, as well as the issuer, audience,
+    // expiry and the nonce we stored in the session. A token that fails is the provider's (or
+    // whoever tampered with it) fault, not ours
+    let claims = id_token
+        .claims(&client.id_token_verifier(), nonce)
+        .context("Failed to verify ID token")
+        .map_err(AuthError::Provider)?;
+
+    Ok((identity(provider, claims), ProviderTokens::from_response(&token)))
+}
+
+fn identity(provider: &str, claims: &CoreIdTokenClaims) -> Identity {
+    let subject = claims.subject().as_str().to_string();
+
+    let name = claims
+        .name()
+        .and_then(|name| name.get(None))
+        .map(|name| name.as_str().to_string())
+        .or_else(|| {
+            claims
+                .preferred_username()
+                .map(|username| username.as_str().to_string())
+        })
+        .unwrap_or_else(|| subject.clone());
+
+    Identity {
+        provider: provider.to_string(),
+        subject,
+        name,
+        email: claims.email().map(|email| email.as_str().to_string()),
+        avatar_url: claims
+            .picture()
+            .and_then(|picture| picture.get(None))
+            .map(|picture| picture.as_str().to_string()),
+    }
+}
@@ -0,0 +1,424 @@
+use std::{collections::BTreeMap, env};
+
+use anyhow::{anyhow, Context};
+use oauth2::{
+    basic::BasicClient, url::Url, AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken,
+    PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, RefreshToken, Scope, TokenResponse,
+    TokenUrl,
+};
+use openidconnect::{core::CoreClient, Nonce};
+use serde::{de::DeserializeOwned, Deserialize};
+
+use crate::{
+    accounts::Identity, http_client::async_http_client, oidc, tokens::ProviderTokens, AppError,
+    AuthError,
+};
+
+/// Configured login providers, keyed by the name used in `/auth/{provider}`.
+pub type Providers = BTreeMap<String, Provider>;
+
+#[derive(Clone)]
+pub struct Provider {
+    pub name: String,
+    kind: ProviderKind,
+    http: reqwest::Client,
+}
+
+#[derive(Clone)]
+enum ProviderKind {
+    OAuth {
+        client: Box<BasicClient>,
+        user_info_url: String,
+        scopes: Vec<String>,
+        mapping: UserInfoMapping,
+    },
+    Oidc {
+        client: Box<CoreClient>,
+        user_info_url: Option<String>,
+    },
+}
+
+/// Plain OAuth2 endpoints of a provider without OpenID Connect discovery.
+pub struct OAuthConfig {
+    pub client_id: String,
+    pub client_secret: String,
+    pub auth_url: String,
+    pub token_url: String,
+    pub user_info_url: String,
+    pub redirect_url: String,
+    pub scopes: Vec<String>,
+}
+
+/// Everything the callback needs to finish the login, stored in the session meanwhile.
+pub struct AuthorizeUrl {
+    pub url: Url,
+    pub csrf_token: CsrfToken,
+    pub pkce_verifier: PkceCodeVerifier,
+    pub nonce: Option<Nonce>,
+}
+
+/// Reads the providers listed in `OAUTH_PROVIDERS` (e.g. `github,google`).
+///
+/// Each provider is configured through env vars prefixed with its upper-cased name. Providers
+/// with an `{NAME}_ISSUER_URL` use OpenID Connect discovery, the others need the plain OAuth2
+/// endpoints. The callback url is always `{BASE_URL}/auth/{provider}/callback`.
+pub async fn providers_from_env(http: reqwest::Client) -> Result<Providers, AppError> {
+    let names = env::var("OAUTH_PROVIDERS").context("Missing OAUTH_PROVIDERS")?;
+    let base_url = env::var("BASE_URL").context("Missing BASE_URL")?;
+
+    let mut providers = Providers::new();
+
+    for name in names.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+        let provider = Provider::from_env(name, &base_url, http.clone()).await?;
+        providers.insert(name.to_string(), provider);
+    }
+
+    Ok(providers)
+}
+
+fn provider_var(name: &str, key: &str) -> Result<String, AppError> {
+    let var = format!("{}_{}", name.to_uppercase(), key);
+    Ok(env::var(&var).with_context(|| format!("Missing {var}"))?)
+}
+
+impl Provider {
+    async fn from_env(name: &str, base_url: &str, http: reqwest::Client) -> Result<Self, AppError> {
+        let client_id = provider_var(name, "CLIENT_ID")?;
+        let client_secret = provider_var(name, "CLIENT_SECRET")?;
+        let redirect_url = format!("{}/auth/{name}/callback", base_url.trim_end_matches('/'));
+
+        if let Ok(issuer_url) = provider_var(name, "ISSUER_URL") {
+            return Provider::oidc(
+                name,
+                issuer_url,
+                client_id,
+                client_secret,
+                redirect_url,
+                http,
+            )
+            .await;
+        }
+
+        let mapping = UserInfoMapping::for_provider(name);
+
+        let user_info_url = match provider_var(name, "USERINFO_URL") {
+            Ok(url) => url,
+            Err(e) => mapping.default_user_info_url().ok_or(e)?.to_string(),
+        };
+
+        let scopes = match provider_var(name, "SCOPES") {
+            Ok(scopes) => scopes.split(',').map(|s| s.trim().to_string()).collect(),
+            Err(_) => mapping.default_scopes(),
+        };
+
+        let config = OAuthConfig {
+            client_id,
+            client_secret,
+            auth_url: provider_var(name, "AUTH_URL")?,
+            token_url: provider_var(name, "TOKEN_URL")?,
+            user_info_url,
+            redirect_url,
+            scopes,
+        };
+
+        Provider::oauth(name, config, http)
+    }
+
+    /// Discovers the endpoints and signing keys of an OpenID Connect provider.
+    pub async fn oidc(
+        name: &str,
+        issuer_url: String,
+        client_id: String,
+        client_secret: String,
+        redirect_url: String,
+        http: reqwest::Client,
+    ) -> Result<Self, AppError> {
+        let (client, user_info_url) = oidc::oidc_client(
+            &http,
+            issuer_url,
+            client_id,
+            client_secret,
+            RedirectUrl::new(redirect_url)?,
+        )
+        .await
+        .with_context(|| format!("Failed to configure {name}"))?;
+
+        Ok(Provider {
+            name: name.to_string(),
+            kind: ProviderKind::Oidc {
+                client: Box::new(client),
+                user_info_url,
+            },
+            http,
+        })
+    }
+
+    pub fn oauth(name: &str, config: OAuthConfig, http: reqwest::Client) -> Result<Self, AppError> {
+        let client = BasicClient::new(
+            ClientId::new(config.client_id),
+            Some(ClientSecret::new(config.client_secret)),
+            AuthUrl::new(config.auth_url)?,
+            Some(TokenUrl::new(config.token_url)?),
+        )
+        .set_redirect_uri(RedirectUrl::new(config.redirect_url)?);
+
+        Ok(Provider {
+            name: name.to_string(),
+            kind: ProviderKind::OAuth {
+                client: Box::new(client),
+                user_info_url: config.user_info_url,
+                scopes: config.scopes,
+                mapping: UserInfoMapping::for_provider(name),
+            },
+            http,
+        })
+    }
+
+    pub fn authorize_url(&self) -> AuthorizeUrl {
+        let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+
+        match &self.kind {
+            ProviderKind::OAuth { client, scopes, .. } => {
+                let (url, csrf_token) = client
+                    .authorize_url(CsrfToken::new_random)
+                    .add_scopes(scopes.iter().cloned().map(Scope::new))
+                    .add_extra_param("prompt", "consent")
+                    .set_pkce_challenge(pkce_challenge)
+                    .url();
+
+                AuthorizeUrl {
+                    url,
+                    csrf_token,
+                    pkce_verifier,
+                    nonce: None,
+                }
+            }
+            ProviderKind::Oidc { client, .. } => {
+                let (url, csrf_token, nonce) = oidc::authorize_url(client, pkce_challenge);
+
+                AuthorizeUrl {
+                    url,
+                    csrf_token,
+                    pkce_verifier,
+                    nonce: Some(nonce),
+                }
+            }
+        }
+    }
+
+    pub fn http(&self) -> &reqwest::Client {
+        &self.http
+    }
+
+    pub fn user_info_url(&self) -> Option<&str> {
+        match &self.kind {
+            ProviderKind::OAuth { user_info_url, .. } => Some(user_info_url),
+            ProviderKind::Oidc { user_info_url, .. } => user_info_url.as_deref(),
+        }
+    }
+
+    /// Exchanges the authorization code and maps the provider's user data onto an [`Identity`].
+    ///
+    /// The tokens are returned as well, so the provider API can be called on the user's behalf.
+    pub async fn fetch_identity(
+        &self,
+        code: String,
+        pkce_verifier: PkceCodeVerifier,
+        nonce: Option<Nonce>,
+    ) -> Result<(Identity, ProviderTokens), AppError> {
+        match &self.kind {
+            ProviderKind::OAuth {
+                client,
+                user_info_url,
+                mapping,
+                ..
+            } => {
+                let token = client
+                    .exchange_code(AuthorizationCode::new(code))
+                    .set_pkce_verifier(pkce_verifier)
+                    .request_async(|request| async_http_client(&self.http, request))
+                    .await
+                    .context("Failed to exchange code for token")?;
+
+                let response = self
+                    .http
+                    .get(user_info_url)
+                    .bearer_auth(token.access_token().secret())
+                    .header("User-Agent", "axum-oauth-example")
+                    .send()
+                    .await
+                    .with_context(|| format!("Failed to get user data from {}", self.name))?;
+
+                if response.status().is_client_error() || response.status().is_server_error() {
+                    let message = response
+                        .text()
+                        .await
+                        .unwrap_or("Something went wrong".to_string());
+                    return Err(AuthError::Provider(anyhow!(message)).into());
+                }
+
+                let user_info = response
+                    .json::<serde_json::Value>()
+                    .await
+                    .context("Failed to read user data")
+                    .map_err(AuthError::Provider)?;
+
+                let identity = mapping
+                    .identity(&self.name, user_info)
+                    .map_err(AuthError::Provider)?;
+
+                Ok((identity, ProviderTokens::from_response(&token)))
+            }
+            ProviderKind::Oidc { client, .. } => {
+                let nonce = nonce.context("Missing nonce for OpenID Connect login")?;
+
+                oidc::fetch_identity(&self.name, client, &self.http, code, pkce_verifier, &nonce)
+                    .await
+            }
+        }
+    }
+
+    /// Trades the refresh token for new tokens. Providers that don't rotate refresh tokens
+    /// keep the old one.
+    pub async fn refresh(&self, refresh_token: &RefreshToken) -> Result<ProviderTokens, AppError> {
+        let mut tokens = match &self.kind {
+            ProviderKind::OAuth { client, .. } => {
+                let token = client
+                    .exchange_refresh_token(refresh_token)
+                    .request_async(|request| async_http_client(&self.http, request))
+                    .await
+                    .context("Failed to refresh token")?;
+
+                ProviderTokens::from_response(&token)
+            }
+            ProviderKind::Oidc { client, .. } => {
+                let token = client
+                    .exchange_refresh_token(refresh_token)
+                    .request_async(|request| async_http_client(&self.http, request))
+                    .await
+                    .context("Failed to refresh token")?;
+
+                ProviderTokens::from_response(&token)
+            }
+        };
+
+        if tokens.refresh_token.is_none() {
+            tokens.refresh_token = Some(refresh_token.clone());
+        }
+
+        Ok(tokens)
+    }
+}
+
+/// How a provider's user-info response is turned into an [`Identity`].
+#[derive(Debug, Clone, Copy)]
+enum UserInfoMapping {
+    Github,
+    Gitlab,
+    // OIDC style `sub`, `name`, `email` and `picture` claims
+    Standard,
+}
+
+impl UserInfoMapping {
+    fn for_provider(name: &str) -> Self {
+        match name {
+            "github" => UserInfoMapping::Github,
+            "gitlab" => UserInfoMapping::Gitlab,
+            _ => UserInfoMapping::Standard,
+        }
+    }
+
+    fn default_user_info_url(self) -> Option<&'static str> {
+        match self {
+            UserInfoMapping::Github => Some("https://api.github.com/user"),
+            UserInfoMapping::Gitlab => Some("https://gitlab.com/api/v4/user"),
+            UserInfoMapping::Standard => None,
+        }
+    }
+
+    fn default_scopes(self) -> Vec<String> {
+        let scopes: &[&str] = match self {
+            UserInfoMapping::Github => &["user:email"],
+            UserInfoMapping::Gitlab => &["read_user"],
+            UserInfoMapping::Standard => &["profile", "email"],
+        };
+
+        scopes.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn identity(self, provider: &str, user_info: serde_json::Value) -> anyhow::Result<Identity> {
+        let provider = provider.to_string();
+
+        let identity = match self {
+            UserInfoMapping::Github => {
+                let user = parse::<GithubUser>(user_info)?;
+
+                Identity {
+                    provider,
+                    subject: user.id.to_string(),
+                    name: user.name.unwrap_or(user.login),
+                    email: user.email,
+                    avatar_url: Some(user.avatar_url),
+                }
+            }
+            UserInfoMapping::Gitlab => {
+                let user = parse::<GitlabUser>(user_info)?;
+
+                Identity {
+                    provider,
+                    subject: user.id.to_string(),
+                    name: user.name.unwrap_or(user.username),
+                    email: user.email,
+                    avatar_url: user.avatar_url,
+                }
+            }
+            UserInfoMapping::Standard => {
+                let user = parse::<StandardUser>(user_info)?;
+
+                Identity {
+                    provider,
+                    name: user
+                        .name
+                        .or(user.preferred_username)
+                        .unwrap_or_else(|| user.sub.clone()),
+                    subject: user.sub,
+                    email: user.email,
+                    avatar_url: user.picture,
+                }
+            }
+        };
+
+        Ok(identity)
+    }
+}
+
+fn parse<T: DeserializeOwned>(user_info: serde_json::Value) -> anyhow::Result<T> {
+    serde_json::from_value(user_info).context("Failed to parse user data")
+}
+
+#[derive(Debug, Deserialize)]
+struct GithubUser {
+    id: u64,
+    login: String,
+    avatar_url: String,
+    email: Option<String>,
+    name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GitlabUser {
+    id: u64,
+    username: String,
+    avatar_url: Option<String>,
+    email: Option<String>,
+    name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StandardUser {
+    sub: String,
+    name: Option<String>,
+    preferred_username: Option<String>,
+    email: Option<String>,
+    picture: Option<String>,
+}
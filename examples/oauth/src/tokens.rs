@@ -0,0 +1,165 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Nonce,
+};
+use anyhow::{anyhow, Context};
+use axum_extra::extract::cookie::Key;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hkdf::Hkdf;
+use oauth2::{AccessToken, RefreshToken, TokenResponse, TokenType};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+// Tokens this close to their expiry are refreshed before being handed out
+const EXPIRY_LEEWAY: Duration = Duration::from_secs(30);
+
+// HKDF info for the token key, so it never equals a key the cookie jar uses
+const TOKEN_KEY_LABEL: &[u8] = b"axum-oauth-example provider tokens v1";
+
+/// Provider tokens of the logged in user, kept in the session to call the provider API later.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProviderTokens {
+    pub access_token: AccessToken,
+    pub refresh_token: Option<RefreshToken>,
+    // Seconds since the unix epoch, `None` if the provider didn't say
+    pub expires_at: Option<u64>,
+}
+
+impl ProviderTokens {
+    pub fn from_response<TT, TR>(response: &TR) -> Self
+    where
+        TT: TokenType,
+        TR: TokenResponse<TT>,
+    {
+        let expires_at = response
+            .expires_in()
+            .map(|expires_in| unix_time(SystemTime::now() + expires_in));
+
+        ProviderTokens {
+            access_token: response.access_token().clone(),
+            refresh_token: response.refresh_token().cloned(),
+            expires_at,
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= unix_time(SystemTime::now() + EXPIRY_LEEWAY))
+    }
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Encrypted [`ProviderTokens`] by provider name, as stored in the session.
+///
+/// The session store only ever sees the sealed tokens, never the plain ones.
+pub type SealedTokens = HashMap<String, String>;
+
+/// Seals provider tokens with AES-256-GCM, using a key derived from the server [`Key`].
+///
+/// The cookie jar encrypts with part of the same key, the derived key keeps the two apart.
+#[derive(Clone)]
+pub struct TokenCipher {
+    cipher: Aes256Gcm,
+}
+
+impl TokenCipher {
+    pub fn new(key: &Key) -> Self {
+        let mut token_key = [0; 32];
+
+        Hkdf::<Sha256>::new(None, key.master())
+            .expand(TOKEN_KEY_LABEL, &mut token_key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+
+        TokenCipher {
+            cipher: Aes256Gcm::new_from_slice(&token_key).expect("32 byte key"),
+        }
+    }
+
+    // The provider name is used as associated data, so tokens can't be swapped between providers
+    pub fn seal(&self, provider: &str, tokens: &ProviderTokens) -> anyhow::Result<String> {
+        let plaintext = serde_json::to_vec(tokens).context("Failed to serialize tokens")?;
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &plaintext,
+                    aad: provider.as_bytes(),
+                },
+            )
+            .map_err(|_| anyhow!("Failed to encrypt tokens"))?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+
+        Ok(URL_SAFE_NO_PAD.encode(sealed))
+    }
+
+    pub fn open(&self, provider: &str, sealed: &str) -> anyhow::Result<ProviderTokens> {
+        let sealed = URL_SAFE_NO_PAD
+            .decode(sealed)
+            .context("Failed to decode tokens")?;
+
+        // 96 bit nonce followed by the ciphertext
+        if sealed.len() < 12 {
+            return Err(anyhow!("Sealed tokens are too short"));
+        }
+
+        let (nonce, ciphertext) = sealed.split_at(12);
+
+        let plaintext = self
+            .cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: provider.as_bytes(),
+                },
+            )
+            .map_err(|_| anyhow!("Failed to decrypt tokens"))?;
+
+        serde_json::from_slice(&plaintext).context("Failed to deserialize tokens")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_and_open() {
+        let key = Key::generate();
+        let cipher = TokenCipher::new(&key);
+
+        let tokens = ProviderTokens {
+            access_token: AccessToken::new("access".to_string()),
+            refresh_token: Some(RefreshToken::new("refresh".to_string())),
+            expires_at: Some(42),
+        };
+
+        let sealed = cipher.seal("github", &tokens).unwrap();
+        let opened = cipher.open("github", &sealed).unwrap();
+
+        assert_eq!(opened.access_token.secret(), "access");
+        assert_eq!(opened.expires_at, Some(42));
+
+        // Bound to the provider, and to the derived key rather than the cookie jar's
+        assert!(cipher.open("gitlab", &sealed).is_err());
+
+        let cookie_cipher = TokenCipher {
+            cipher: Aes256Gcm::new_from_slice(key.encryption()).unwrap(),
+        };
+
+        assert!(cookie_cipher.open("github", &sealed).is_err());
+    }
+}
@@ -1,3 +1,51 @@
+const METRICS = ["cpu", "memory", "swap", "disk", "net", "processes"];
+
+function formatBytes(bytes) {
+  const units = ["B", "KiB", "MiB", "GiB", "TiB"];
+  let unit = 0;
+
+  while (bytes >= 1024 && unit < units.length - 1) {
+    bytes /= 1024;
+    unit += 1;
+  }
+
+  return `${bytes.toFixed(1)} ${units[unit]}`;
+}
+
+function formatPercent(value) {
+  return `${value.toFixed(1)}%`;
+}
+
+function formatUsage({ used, total }) {
+  const percent = total > 0 ? (used / total) * 100 : 0;
+  return `${formatBytes(used)} / ${formatBytes(total)} (${formatPercent(percent)})`;
+}
+
+// Each metric renders to a list of lines
+const renderers = {
+  cpu: (data) => [
+    `Total: ${formatPercent(data.usage)}`,
+    ...data.cores.map((core) => `${core.name}: ${formatPercent(core.usage)}`),
+  ],
+  memory: (data) => [formatUsage(data)],
+  swap: (data) => [formatUsage(data)],
+  disk: (data) =>
+    data.disks.map(
+      (disk) =>
+        `${disk.mount_point} (${disk.name}): ${formatBytes(disk.available)} free of ${formatBytes(disk.total)}`,
+    ),
+  net: (data) =>
+    data.interfaces.map(
+      (iface) =>
+        `${iface.name}: ↓ ${formatBytes(iface.received)} ↑ ${formatBytes(iface.transmitted)}`,
+    ),
+  processes: (data) =>
+    data.processes.map(
+      (process) =>
+        `${process.pid} ${process.name}: ${formatPercent(process.cpu)} CPU, ${formatBytes(process.memory)}`,
+    ),
+};
+
 function main() {
   const messageContainer = document.querySelector(".message-container");
   const container = document.querySelector(".container");
@@ -14,16 +62,41 @@ function main() {
     });
   }
 
-  const eventSource = new EventSource("http://localhost:3000/sse");
+  // One panel per metric, updated in place with every new sample
+  const panels = {};
 
-  eventSource.addEventListener("message", (event) => {
-    console.log("message", event);
-    addRow(event.data, "data");
-  });
+  function panel(metric) {
+    if (!panels[metric]) {
+      const panelElement = document.createElement("div");
+      panelElement.classList.add("message", "data");
+
+      const title = document.createElement("h3");
+      title.textContent = metric;
+
+      const body = document.createElement("pre");
+
+      panelElement.append(title, body);
+      messageContainer.appendChild(panelElement);
+
+      panels[metric] = body;
+    }
+
+    return panels[metric];
+  }
+
+  // Pass the page's query on, so `/?metrics=cpu,net` only shows those metrics
+  const eventSource = new EventSource(`/sse${window.location.search}`);
+
+  for (const metric of METRICS) {
+    eventSource.addEventListener(metric, (event) => {
+      const data = JSON.parse(event.data);
+      panel(metric).textContent = renderers[metric](data).join("\n");
+    });
+  }
 
   eventSource.addEventListener("error", (event) => {
     console.log("error", event);
-    addRow("Error: something went wrong", "error");
+    addRow(event.data ? `Error: ${event.data}` : "Error: something went wrong", "error");
   });
 
   eventSource.addEventListener("open", (event) => {
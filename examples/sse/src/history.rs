@@ -0,0 +1,53 @@
+use std::collections::VecDeque;
+
+/// The most recent events sent to clients, so a reconnecting client can catch up.
+///
+/// Ids are assigned here and increase monotonically, they are never reused.
+pub struct History<T> {
+    next_id: u64,
+    capacity: usize,
+    events: VecDeque<(u64, T)>,
+}
+
+impl<T: Clone> History<T> {
+    pub fn new(capacity: usize) -> Self {
+        History {
+            next_id: 0,
+            capacity,
+            events: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Records an event, dropping the oldest one when full, and returns its id.
+    pub fn push(&mut self, data: T) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        if self.events.len() == self.capacity {
+            self.events.pop_front();
+        }
+        self.events.push_back((id, data));
+
+        id
+    }
+
+    /// Events after `last_id`, oldest first.
+    ///
+    /// If `last_id` already fell out of the buffer the client missed some events, the best we
+    /// can do then is to send everything we still have. Returns `None` if `last_id` was never
+    /// handed out, ids restart at 0 with the server so the client got it from an earlier run.
+    pub fn since(&self, last_id: u64) -> Option<Vec<(u64, T)>> {
+        if last_id >= self.next_id {
+            return None;
+        }
+
+        let events = self
+            .events
+            .iter()
+            .filter(|(id, _)| *id > last_id)
+            .cloned()
+            .collect();
+
+        Some(events)
+    }
+}
@@ -0,0 +1,122 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use serde_json::Value;
+use tokio::sync::broadcast;
+
+/// A message published to a topic, with its id in that topic.
+pub type Message = (u64, Arc<Value>);
+
+/// Fans messages published to a topic out to everyone subscribed to it.
+///
+/// Each topic buffers at most `capacity` messages. Publishers never wait for slow subscribers,
+/// a subscriber that falls more than `capacity` messages behind skips the oldest ones instead.
+///
+/// Anyone can subscribe, so at most `max_topics` topics exist at a time.
+pub struct Hub {
+    capacity: usize,
+    max_topics: usize,
+    topics: Mutex<HashMap<String, Topic>>,
+}
+
+struct Topic {
+    next_id: u64,
+    sender: broadcast::Sender<Message>,
+}
+
+impl Hub {
+    pub fn new(capacity: usize, max_topics: usize) -> Self {
+        Hub {
+            capacity,
+            max_topics,
+            topics: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns `None` if the topic doesn't exist yet and there are too many topics already.
+    pub fn subscribe(&self, topic: &str) -> Option<broadcast::Receiver<Message>> {
+        let mut topics = self.topics.lock().unwrap();
+
+        if let Some(entry) = topics.get(topic) {
+            return Some(entry.sender.subscribe());
+        }
+
+        // Receivers don't tell the hub when they are dropped, so topics nobody listens to anymore
+        // are only cleaned up here and in `publish`
+        if topics.len() >= self.max_topics {
+            topics.retain(|_, entry| entry.sender.receiver_count() > 0);
+        }
+
+        if topics.len() >= self.max_topics {
+            return None;
+        }
+
+        let (sender, receiver) = broadcast::channel(self.capacity);
+
+        topics.insert(topic.to_string(), Topic { next_id: 0, sender });
+
+        Some(receiver)
+    }
+
+    /// Returns the number of subscribers the message was sent to.
+    pub fn publish(&self, topic: &str, payload: Value) -> usize {
+        let mut topics = self.topics.lock().unwrap();
+
+        let Some(entry) = topics.get_mut(topic) else {
+            return 0;
+        };
+
+        // Nobody is listening anymore, forget the topic instead of keeping it around forever
+        if entry.sender.receiver_count() == 0 {
+            topics.remove(topic);
+            return 0;
+        }
+
+        let id = entry.next_id;
+        entry.next_id += 1;
+
+        entry.sender.send((id, Arc::new(payload))).unwrap_or(0)
+    }
+
+    /// Drops every topic, which ends the streams of all subscribers.
+    pub fn close(&self) {
+        self.topics.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_max_topics() {
+        let hub = Hub::new(4, 2);
+
+        let a = hub.subscribe("a").unwrap();
+        let b = hub.subscribe("b").unwrap();
+
+        assert!(hub.subscribe("c").is_none());
+
+        // More subscribers for an existing topic are fine
+        let _a2 = hub.subscribe("a").unwrap();
+
+        // Once nobody listens to `b` anymore it makes room for `c`
+        drop(b);
+
+        let mut c = hub.subscribe("c").unwrap();
+
+        assert_eq!(hub.publish("c", json!(1)), 1);
+        assert_eq!(*c.try_recv().unwrap().1, json!(1));
+        assert_eq!(hub.publish("b", json!(2)), 0);
+
+        // Topics still in use are never pruned
+        assert!(hub.subscribe("d").is_none());
+
+        drop(a);
+        assert_eq!(hub.publish("a", json!(3)), 1);
+    }
+}
@@ -1,16 +1,37 @@
-use std::{convert::Infallible, path::PathBuf, time::Duration};
+mod history;
+mod hub;
+mod metrics;
+mod sampler;
+
+use std::{
+    collections::HashSet,
+    convert::Infallible,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
     response::{
         sse::{Event, KeepAlive},
         Sse,
     },
     routing::get,
-    Router,
+    Json, Router,
+};
+use axum_extra::{
+    headers::{authorization::Bearer, Authorization},
+    TypedHeader,
 };
-use axum_extra::TypedHeader;
 use futures::{stream, Stream};
-use tokio::{net::TcpListener, sync::mpsc};
+use history::History;
+use hub::Hub;
+use metrics::{Metric, Sample};
+use sampler::Sampler;
+use serde::{Deserialize, Serialize};
+use tokio::{net::TcpListener, sync::broadcast::error::RecvError};
 use tokio_stream::StreamExt;
 use tower_http::{services::ServeDir, trace::TraceLayer};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
@@ -22,104 +43,296 @@ async fn main() {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let app = app();
+    // How often the system is sampled, shared by all connections
+    let sample_interval = std::env::var("SAMPLE_INTERVAL_MS")
+        .ok()
+        .and_then(|ms| ms.parse().ok())
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_SAMPLE_INTERVAL);
+
+    // Publishing to the hub needs `Authorization: Bearer <PUBLISH_TOKEN>`
+    let publish_token = std::env::var("PUBLISH_TOKEN").ok();
+
+    if publish_token.is_none() {
+        tracing::warn!("PUBLISH_TOKEN is not set, publishing to the event hub is disabled");
+    }
+
+    let state = AppState::new(sample_interval, publish_token);
+    let sampler = state.sampler.clone();
+    let hub = state.hub.clone();
 
     let listener = TcpListener::bind("localhost:3000").await.unwrap();
 
     tracing::debug!("listening on {}", listener.local_addr().unwrap());
 
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(listener, app(state))
+        .with_graceful_shutdown(async move {
+            tokio::signal::ctrl_c().await.unwrap();
+
+            // SSE streams never end by themselves, closing their sources ends all of them
+            sampler.stop();
+            hub.close();
+        })
+        .await
+        .unwrap();
 }
 
-fn app() -> Router {
+const DEFAULT_SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+// Number of samples kept around for clients that reconnect, a few rounds of every metric
+const HISTORY_SIZE: usize = 256;
+// How long browsers should wait before reconnecting after losing the connection
+const RETRY_AFTER: Duration = Duration::from_secs(2);
+// Messages buffered per hub topic, subscribers further behind than this miss messages
+const TOPIC_CAPACITY: usize = 64;
+// Topics with subscribers at any one time, subscribing to yet another topic is refused
+const MAX_TOPICS: usize = 1024;
+
+#[derive(Clone)]
+struct AppState {
+    history: Arc<Mutex<History<Sample>>>,
+    sampler: Arc<Sampler>,
+    hub: Arc<Hub>,
+    publish_token: Option<Arc<str>>,
+}
+
+impl AppState {
+    fn new(sample_interval: Duration, publish_token: Option<String>) -> Self {
+        let history = Arc::new(Mutex::new(History::new(HISTORY_SIZE)));
+
+        AppState {
+            sampler: Sampler::new(sample_interval, history.clone()),
+            history,
+            hub: Arc::new(Hub::new(TOPIC_CAPACITY, MAX_TOPICS)),
+            publish_token: publish_token.map(Into::into),
+        }
+    }
+}
+
+// Shared by every SSE endpoint
+fn keep_alive() -> KeepAlive {
+    KeepAlive::new()
+        .interval(Duration::from_millis(500))
+        .text("Keep alive message")
+}
+
+fn app(state: AppState) -> Router {
     let assets_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("assets");
     println!("assets_dir: {:?}", assets_dir);
     let serve_dir = ServeDir::new(assets_dir).append_index_html_on_directories(true);
     Router::new()
         .fallback_service(serve_dir)
         .route("/sse", get(sse_handler))
+        .route(
+            "/events/{topic}",
+            get(subscribe_handler).post(publish_handler),
+        )
         .layer(TraceLayer::new_for_http())
+        .with_state(state)
+}
+
+#[derive(Deserialize)]
+struct SseParams {
+    // Comma separated, e.g. `cpu,net`. All metrics if missing
+    metrics: Option<String>,
+}
+
+// Each sample is sent as an event named after its metric, with the sample as JSON data
+fn sample_event(id: u64, sample: &Sample) -> Event {
+    Event::default()
+        .event(sample.metric().name())
+        .id(id.to_string())
+        .json_data(sample)
+        .expect("samples serialize to JSON")
 }
 
 async fn sse_handler(
+    State(state): State<AppState>,
     TypedHeader(user_agent): TypedHeader<headers::UserAgent>,
-) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    Query(params): Query<SseParams>,
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, String)> {
     tracing::debug!("SSE connection from {}", user_agent.to_string());
 
-    let (tx, rx) = mpsc::channel::<String>(32);
+    let metrics = match params.metrics {
+        Some(list) => Metric::parse_list(&list).map_err(|err| (StatusCode::BAD_REQUEST, err))?,
+        None => HashSet::from(Metric::ALL),
+    };
+
+    // Sent by browsers when reconnecting, with the id of the last event they got
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+
+    // Subscribe before looking at the history, so no sample can fall in between the two
+    let rx = state.sampler.subscribe();
+
+    let replay = last_event_id.and_then(|id| state.history.lock().unwrap().since(id));
 
-    let mut sys = sysinfo::System::new_all();
+    // An id from before a restart means nothing now, the client starts over like a new one
+    let last_event_id = last_event_id.filter(|_| replay.is_some());
+
+    let replay = replay
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|(_, sample)| metrics.contains(&sample.metric()))
+        .collect::<Vec<_>>();
+
+    // Live samples up to here were either replayed or already seen by the client
+    let skip_up_to = replay.last().map(|(id, _)| *id).or(last_event_id);
+
+    let replay = stream::iter(replay);
+
+    // Ends with an error event once the sampler is gone, instead of waiting forever
+    let live = stream::unfold(Some((rx, metrics)), move |state| async move {
+        let (mut rx, metrics) = state?;
 
-    tokio::spawn(async move {
         loop {
-            sys.refresh_all();
-            sys.refresh_cpu_specifics(sysinfo::CpuRefreshKind::everything());
-            sys.refresh_memory_specifics(sysinfo::MemoryRefreshKind::everything());
-            let cpu_usage = sys.global_cpu_usage();
-            let used_memory = sys.used_memory() as f64;
-            let total_memory = sys.total_memory() as f64;
-            let memory_usage = used_memory / total_memory;
-            let message = format!(
-                "CPU: {}%, Memory: {}%",
-                cpu_usage,
-                (memory_usage * 100.0) as u64 as f64 / 100.0
-            );
-
-            if let Err(_) = tx.send(message).await {
-                tracing::error!("Failed to send message to channel");
-                break;
-            }
+            match rx.recv().await {
+                Ok((id, _)) if skip_up_to.is_some_and(|skip_up_to| id <= skip_up_to) => {}
+                Ok((_, sample)) if !metrics.contains(&sample.metric()) => {}
+                Ok((id, sample)) => return Some((sample_event(id, &sample), Some((rx, metrics)))),
+                // Too slow to keep up, skip ahead to the samples still in the channel
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::debug!(skipped, "SSE client lagging behind");
+                }
+                // Browsers reconnect after `RETRY_AFTER`, which starts a new sampler
+                Err(RecvError::Closed) => {
+                    let event = Event::default()
+                        .event("error")
+                        .data("No message from server");
 
-            tokio::time::sleep(Duration::from_millis(500)).await;
+                    return Some((event, None));
+                }
+            }
         }
     });
 
-    let stream = stream::unfold(rx, |mut rx| async {
-        if let Some(message) = rx.recv().await {
-            return Some((Event::default().data(message), rx));
-        }
+    // The retry hint only has to be sent once per connection
+    let mut first = true;
 
-        Some((
-            Event::default()
-                .event("error")
-                .data("No message from server"),
-            rx,
-        ))
+    let stream = replay
+        .map(|(id, sample)| sample_event(id, &sample))
+        .chain(live)
+        .map(move |event| {
+            if std::mem::take(&mut first) {
+                event.retry(RETRY_AFTER)
+            } else {
+                event
+            }
+        })
+        .map(Ok);
+
+    Ok(Sse::new(stream).keep_alive(keep_alive()))
+}
+
+// Topics are path segments, keep them short and boring
+fn check_topic(topic: &str) -> Result<(), (StatusCode, String)> {
+    let valid = (1..=64).contains(&topic.len())
+        && topic
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+
+    if valid {
+        Ok(())
+    } else {
+        Err((StatusCode::BAD_REQUEST, format!("invalid topic `{topic}`")))
+    }
+}
+
+async fn subscribe_handler(
+    State(state): State<AppState>,
+    Path(topic): Path<String>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, String)> {
+    check_topic(&topic)?;
+
+    tracing::debug!(topic, "hub subscription");
+
+    let rx = state.hub.subscribe(&topic).ok_or((
+        StatusCode::SERVICE_UNAVAILABLE,
+        "too many topics".to_string(),
+    ))?;
+
+    let stream = stream::unfold(rx, |mut rx| async move {
+        let event = match rx.recv().await {
+            Ok((id, payload)) => Event::default()
+                .id(id.to_string())
+                .json_data(&*payload)
+                .expect("JSON values serialize"),
+            // A slow subscriber skips the oldest messages, tell it how many it lost
+            Err(RecvError::Lagged(skipped)) => {
+                Event::default().event("lagged").data(skipped.to_string())
+            }
+            Err(RecvError::Closed) => return None,
+        };
+
+        Some((event, rx))
     })
     .map(Ok);
 
-    Sse::new(stream).keep_alive(
-        KeepAlive::new()
-            .interval(Duration::from_millis(500))
-            .text("Keep alive message"),
-    )
+    Ok(Sse::new(stream).keep_alive(keep_alive()))
+}
+
+#[derive(Serialize)]
+struct Published {
+    subscribers: usize,
+}
+
+async fn publish_handler(
+    State(state): State<AppState>,
+    Path(topic): Path<String>,
+    authorization: Option<TypedHeader<Authorization<Bearer>>>,
+    Json(payload): Json<serde_json::Value>,
+) -> Result<Json<Published>, (StatusCode, String)> {
+    check_topic(&topic)?;
+
+    let authorized = match (&state.publish_token, authorization) {
+        (Some(token), Some(TypedHeader(Authorization(bearer)))) => {
+            constant_time_eq(token.as_bytes(), bearer.token().as_bytes())
+        }
+        _ => false,
+    };
+
+    if !authorized {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            "invalid publish token".to_string(),
+        ));
+    }
+
+    let subscribers = state.hub.publish(&topic, payload);
+
+    tracing::debug!(topic, subscribers, "published to hub");
+
+    Ok(Json(Published { subscribers }))
+}
+
+// Doesn't bail out at the first differing byte, so the token can't be guessed from timings
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
 }
 
 #[cfg(test)]
 mod tests {
     use eventsource_stream::Eventsource;
-    use reqwest_eventsource::RequestBuilderExt;
 
     use super::*;
 
-    async fn spawn_app(host: impl Into<String>) -> String {
-        let host = host.into();
-        let port = 3000;
-        let listener = TcpListener::bind(format!("{}:{}", &host, port))
-            .await
-            .unwrap();
-        let app = app();
+    async fn spawn_app(state: AppState) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let app = app(state);
 
         tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
 
-        format!("http://{}:{}", host, port)
+        url
     }
 
     #[tokio::test]
     async fn test_sse_handler() {
-        let url = spawn_app("localhost").await;
+        let url = spawn_app(AppState::new(Duration::from_millis(100), None)).await;
         let mut event_stream = reqwest::Client::new()
-            .get(format!("{}/sse", url))
+            .get(format!("{}/sse?metrics=cpu,memory", url))
             .header("User-Agent", "sse-integration-tests")
             .send()
             .await
@@ -128,12 +341,18 @@ mod tests {
             .eventsource()
             .take(3);
 
-        let mut messages = Vec::<String>::new();
+        let mut messages = Vec::<serde_json::Value>::new();
+        let mut types = Vec::<String>::new();
+        let mut ids = Vec::<u64>::new();
+        let mut retry = None;
 
         while let Some(event) = event_stream.next().await {
             match event {
                 Ok(event) => {
-                    messages.push(event.data);
+                    retry = retry.or(event.retry);
+                    ids.push(event.id.parse().unwrap());
+                    types.push(event.event);
+                    messages.push(serde_json::from_str(&event.data).unwrap());
                 }
                 Err(e) => {
                     eprintln!("Error: {:?}", e);
@@ -145,8 +364,141 @@ mod tests {
         println!("Messages: {:#?}", messages);
 
         assert_eq!(messages.len(), 3);
-        assert!(messages[0].contains("CPU"));
-        assert!(messages[1].contains("Memory"));
-        assert!(messages[2].contains("CPU"));
+        // Only the requested metrics, in the order they are sampled
+        assert_eq!(types, ["cpu", "memory", "cpu"]);
+        assert!(messages[0]["cores"].is_array());
+        assert!(messages[1]["total"].as_u64().unwrap() > 0);
+        assert_eq!(retry, Some(RETRY_AFTER));
+        assert!(ids.windows(2).all(|ids| ids[0] < ids[1]));
+
+        // Reconnect as if the last event got lost, it should be replayed from the history
+        let event = reqwest::Client::new()
+            .get(format!("{}/sse?metrics=cpu,memory", url))
+            .header("User-Agent", "sse-integration-tests")
+            .header("Last-Event-ID", ids[1].to_string())
+            .send()
+            .await
+            .unwrap()
+            .bytes_stream()
+            .eventsource()
+            .next()
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(event.id, ids[2].to_string());
+        assert_eq!(event.event, "cpu");
+        assert_eq!(event.retry, Some(RETRY_AFTER));
+
+        let response = reqwest::Client::new()
+            .get(format!("{}/sse?metrics=cpu,gpu", url))
+            .header("User-Agent", "sse-integration-tests")
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_sse_stale_last_event_id() {
+        let url = spawn_app(AppState::new(Duration::from_millis(100), None)).await;
+
+        // As if the client last saw an event from an earlier run of the server, which got further
+        let mut event_stream = reqwest::Client::new()
+            .get(format!("{}/sse?metrics=cpu", url))
+            .header("User-Agent", "sse-integration-tests")
+            .header("Last-Event-ID", "1000000")
+            .send()
+            .await
+            .unwrap()
+            .bytes_stream()
+            .eventsource();
+
+        let event = tokio::time::timeout(Duration::from_secs(5), event_stream.next())
+            .await
+            .expect("live events should not be skipped")
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(event.event, "cpu");
+        assert!(event.id.parse::<u64>().unwrap() < 1000000);
+        assert_eq!(event.retry, Some(RETRY_AFTER));
+    }
+
+    #[tokio::test]
+    async fn test_sse_ends_when_sampler_stops() {
+        let state = AppState::new(Duration::from_millis(100), None);
+        let sampler = state.sampler.clone();
+        let url = spawn_app(state).await;
+
+        let mut event_stream = reqwest::Client::new()
+            .get(format!("{}/sse?metrics=cpu", url))
+            .header("User-Agent", "sse-integration-tests")
+            .send()
+            .await
+            .unwrap()
+            .bytes_stream()
+            .eventsource();
+
+        let event = event_stream.next().await.unwrap().unwrap();
+        assert_eq!(event.event, "cpu");
+
+        sampler.stop();
+
+        // Whatever was still in flight, then one error event and the end of the stream
+        let events = tokio::time::timeout(Duration::from_secs(5), event_stream.collect::<Vec<_>>())
+            .await
+            .expect("stream should end once the sampler is gone");
+
+        let last = events.last().unwrap().as_ref().unwrap();
+        assert_eq!(last.event, "error");
+        assert!(events[..events.len() - 1]
+            .iter()
+            .all(|event| event.as_ref().unwrap().event == "cpu"));
+    }
+
+    #[tokio::test]
+    async fn test_hub_publish() {
+        let url = spawn_app(AppState::new(
+            Duration::from_millis(100),
+            Some("secret".to_string()),
+        ))
+        .await;
+        let client = reqwest::Client::new();
+
+        let mut event_stream = client
+            .get(format!("{}/events/news", url))
+            .send()
+            .await
+            .unwrap()
+            .bytes_stream()
+            .eventsource();
+
+        let response = client
+            .post(format!("{}/events/news", url))
+            .json(&serde_json::json!({ "title": "hello" }))
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = client
+            .post(format!("{}/events/news", url))
+            .bearer_auth("secret")
+            .json(&serde_json::json!({ "title": "hello" }))
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let published = response.json::<serde_json::Value>().await.unwrap();
+        assert_eq!(published["subscribers"], 1);
+
+        let event = event_stream.next().await.unwrap().unwrap();
+        assert_eq!(event.id, "0");
+        assert_eq!(event.data, r#"{"title":"hello"}"#);
     }
 }
@@ -0,0 +1,222 @@
+use std::{cmp::Ordering, collections::HashSet, str::FromStr};
+
+use serde::Serialize;
+use sysinfo::{Disks, Networks, ProcessesToUpdate, System};
+
+// Only the busiest processes are sent, the full list is huge
+const TOP_PROCESSES: usize = 10;
+
+/// The kinds of samples clients can subscribe to, each one sent as its own SSE event type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Metric {
+    Cpu,
+    Memory,
+    Swap,
+    Disk,
+    Net,
+    Processes,
+}
+
+impl Metric {
+    pub const ALL: [Metric; 6] = [
+        Metric::Cpu,
+        Metric::Memory,
+        Metric::Swap,
+        Metric::Disk,
+        Metric::Net,
+        Metric::Processes,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Metric::Cpu => "cpu",
+            Metric::Memory => "memory",
+            Metric::Swap => "swap",
+            Metric::Disk => "disk",
+            Metric::Net => "net",
+            Metric::Processes => "processes",
+        }
+    }
+
+    /// Parses a comma separated list like `cpu,net`.
+    pub fn parse_list(list: &str) -> Result<HashSet<Metric>, String> {
+        list.split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::parse)
+            .collect()
+    }
+}
+
+impl FromStr for Metric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Metric::ALL
+            .into_iter()
+            .find(|metric| metric.name() == s)
+            .ok_or_else(|| format!("unknown metric `{s}`"))
+    }
+}
+
+/// One sample of a [`Metric`], serialized as the data of its SSE event.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum Sample {
+    Cpu {
+        // Percentage over all cores
+        usage: f32,
+        cores: Vec<CoreUsage>,
+    },
+    Memory(Usage),
+    Swap(Usage),
+    Disk {
+        disks: Vec<DiskUsage>,
+    },
+    Net {
+        interfaces: Vec<NetworkUsage>,
+    },
+    Processes {
+        processes: Vec<ProcessUsage>,
+    },
+}
+
+impl Sample {
+    pub fn metric(&self) -> Metric {
+        match self {
+            Sample::Cpu { .. } => Metric::Cpu,
+            Sample::Memory(_) => Metric::Memory,
+            Sample::Swap(_) => Metric::Swap,
+            Sample::Disk { .. } => Metric::Disk,
+            Sample::Net { .. } => Metric::Net,
+            Sample::Processes { .. } => Metric::Processes,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CoreUsage {
+    pub name: String,
+    pub usage: f32,
+}
+
+/// Used and total bytes.
+#[derive(Debug, Clone, Serialize)]
+pub struct Usage {
+    pub used: u64,
+    pub total: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DiskUsage {
+    pub name: String,
+    pub mount_point: String,
+    pub available: u64,
+    pub total: u64,
+}
+
+/// Bytes received and transmitted since the previous sample.
+#[derive(Debug, Clone, Serialize)]
+pub struct NetworkUsage {
+    pub name: String,
+    pub received: u64,
+    pub transmitted: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProcessUsage {
+    pub pid: u32,
+    pub name: String,
+    pub cpu: f32,
+    pub memory: u64,
+}
+
+/// Keeps the `sysinfo` handles around between samples, some values are deltas.
+pub struct Collector {
+    sys: System,
+    disks: Disks,
+    networks: Networks,
+}
+
+impl Collector {
+    pub fn new() -> Self {
+        Collector {
+            sys: System::new(),
+            disks: Disks::new_with_refreshed_list(),
+            networks: Networks::new_with_refreshed_list(),
+        }
+    }
+
+    /// Samples every metric, in the order of [`Metric::ALL`].
+    pub fn collect(&mut self) -> Vec<Sample> {
+        self.sys.refresh_cpu_usage();
+        self.sys.refresh_memory();
+        self.sys.refresh_processes(ProcessesToUpdate::All, true);
+        self.disks.refresh(true);
+        self.networks.refresh(true);
+
+        let cores = self
+            .sys
+            .cpus()
+            .iter()
+            .map(|cpu| CoreUsage {
+                name: cpu.name().to_string(),
+                usage: cpu.cpu_usage(),
+            })
+            .collect();
+
+        let disks = self
+            .disks
+            .iter()
+            .map(|disk| DiskUsage {
+                name: disk.name().to_string_lossy().into_owned(),
+                mount_point: disk.mount_point().to_string_lossy().into_owned(),
+                available: disk.available_space(),
+                total: disk.total_space(),
+            })
+            .collect();
+
+        let interfaces = self
+            .networks
+            .iter()
+            .map(|(name, network)| NetworkUsage {
+                name: name.clone(),
+                received: network.received(),
+                transmitted: network.transmitted(),
+            })
+            .collect();
+
+        let mut processes = self
+            .sys
+            .processes()
+            .values()
+            .map(|process| ProcessUsage {
+                pid: process.pid().as_u32(),
+                name: process.name().to_string_lossy().into_owned(),
+                cpu: process.cpu_usage(),
+                memory: process.memory(),
+            })
+            .collect::<Vec<_>>();
+
+        processes.sort_by(|a, b| b.cpu.partial_cmp(&a.cpu).unwrap_or(Ordering::Equal));
+        processes.truncate(TOP_PROCESSES);
+
+        vec![
+            Sample::Cpu {
+                usage: self.sys.global_cpu_usage(),
+                cores,
+            },
+            Sample::Memory(Usage {
+                used: self.sys.used_memory(),
+                total: self.sys.total_memory(),
+            }),
+            Sample::Swap(Usage {
+                used: self.sys.used_swap(),
+                total: self.sys.total_swap(),
+            }),
+            Sample::Disk { disks },
+            Sample::Net { interfaces },
+            Sample::Processes { processes },
+        ]
+    }
+}
@@ -0,0 +1,104 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{sync::broadcast, task::AbortHandle};
+
+use crate::{
+    history::History,
+    metrics::{Collector, Sample},
+};
+
+// Samples a subscriber may fall behind before it starts missing some, a few rounds of all metrics
+const CHANNEL_CAPACITY: usize = 64;
+
+/// Samples the system once for all SSE streams and broadcasts the result to them.
+///
+/// The sampling task is started by the first subscriber and stops by itself once the last one is
+/// gone, so an idle server doesn't keep refreshing system info.
+///
+/// Subscribers see the channel close when the task dies or is [stopped](Sampler::stop). The
+/// next subscriber starts a new task.
+pub struct Sampler {
+    interval: Duration,
+    history: Arc<Mutex<History<Sample>>>,
+    // `None` while no sampling task is running
+    running: Mutex<Option<Running>>,
+}
+
+struct Running {
+    // Weak, so the channel closes as soon as the task is gone, even if it panicked
+    sender: broadcast::WeakSender<(u64, Sample)>,
+    task: AbortHandle,
+}
+
+impl Sampler {
+    pub fn new(interval: Duration, history: Arc<Mutex<History<Sample>>>) -> Arc<Self> {
+        Arc::new(Sampler {
+            interval,
+            history,
+            running: Mutex::new(None),
+        })
+    }
+
+    /// Samples with their event ids, starting with the next one taken.
+    pub fn subscribe(self: &Arc<Self>) -> broadcast::Receiver<(u64, Sample)> {
+        let mut running = self.running.lock().unwrap();
+
+        if let Some(sender) = running
+            .as_ref()
+            .and_then(|running| running.sender.upgrade())
+        {
+            return sender.subscribe();
+        }
+
+        let (tx, rx) = broadcast::channel(CHANNEL_CAPACITY);
+
+        *running = Some(Running {
+            sender: tx.downgrade(),
+            task: tokio::spawn(self.clone().run(tx)).abort_handle(),
+        });
+
+        rx
+    }
+
+    /// Stops the sampling task, which closes the channel of every subscriber.
+    pub fn stop(&self) {
+        if let Some(running) = self.running.lock().unwrap().take() {
+            running.task.abort();
+        }
+    }
+
+    async fn run(self: Arc<Self>, tx: broadcast::Sender<(u64, Sample)>) {
+        tracing::debug!("starting sampler");
+
+        let mut collector = Collector::new();
+        let mut interval = tokio::time::interval(self.interval);
+
+        loop {
+            interval.tick().await;
+
+            let samples = collector.collect();
+
+            // Checked under the lock, so `subscribe` can't pick up a sender that is going away
+            let mut running = self.running.lock().unwrap();
+
+            if tx.receiver_count() == 0 {
+                *running = None;
+                break;
+            }
+
+            let mut history = self.history.lock().unwrap();
+
+            for sample in samples {
+                let id = history.push(sample.clone());
+
+                // Can't fail, there is at least one receiver and we hold the lock `subscribe` needs
+                let _ = tx.send((id, sample));
+            }
+        }
+
+        tracing::debug!("no subscribers left, stopping sampler");
+    }
+}
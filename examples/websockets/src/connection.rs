@@ -0,0 +1,280 @@
+use std::time::Duration;
+
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket};
+use futures::{stream::SplitSink, SinkExt, StreamExt};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::{sleep_until, Instant},
+};
+
+use crate::protocol::{Command, CounterState, Encoding, Notification, Outbound, INVALID_PARAMS};
+
+const DEFAULT_INTERVAL: Duration = Duration::from_millis(300);
+const MIN_INTERVAL: Duration = Duration::from_millis(50);
+const MAX_INTERVAL: Duration = Duration::from_secs(60);
+
+// How long sending the close frame may take before the connection is just dropped
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Timeouts and limits of a connection.
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    /// How often the server pings the client.
+    pub ping_interval: Duration,
+    /// How long the client has to answer a ping before it is considered gone.
+    pub pong_timeout: Duration,
+    /// How long a connection with a stopped counter may go without a message from the client.
+    pub idle_timeout: Duration,
+    /// Messages queued for the client, a client that falls further behind is disconnected.
+    pub outbound_queue: usize,
+    /// Largest frame accepted from the client, in bytes.
+    pub max_frame_size: usize,
+    /// Largest message accepted from the client after putting its fragments back together.
+    pub max_message_size: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            ping_interval: Duration::from_secs(15),
+            pong_timeout: Duration::from_secs(10),
+            idle_timeout: Duration::from_secs(60),
+            outbound_queue: 32,
+            // Commands are tiny, anything bigger is a broken or malicious client
+            max_frame_size: 16 << 10,
+            max_message_size: 64 << 10,
+        }
+    }
+}
+
+/// State of the counter of one connection, changed by the client's commands.
+struct Counter {
+    count: u64,
+    running: bool,
+    interval: Duration,
+}
+
+impl Counter {
+    fn state(&self) -> CounterState {
+        CounterState {
+            running: self.running,
+            interval_ms: self.interval.as_millis() as u64,
+        }
+    }
+
+    fn apply(&mut self, command: Command) -> Result<CounterState, String> {
+        match command {
+            Command::Start => self.running = true,
+            Command::Stop => self.running = false,
+            Command::SetInterval { interval_ms } => {
+                let interval = Duration::from_millis(interval_ms);
+
+                if !(MIN_INTERVAL..=MAX_INTERVAL).contains(&interval) {
+                    return Err(format!(
+                        "interval_ms must be between {} and {}",
+                        MIN_INTERVAL.as_millis(),
+                        MAX_INTERVAL.as_millis()
+                    ));
+                }
+
+                self.interval = interval;
+            }
+        }
+
+        Ok(self.state())
+    }
+}
+
+/// Why the server ends a connection, sent to the client as the close frame.
+fn close_frame(code: u16, reason: &str) -> CloseFrame {
+    CloseFrame {
+        code,
+        reason: reason.into(),
+    }
+}
+
+pub async fn handle_ws(ws: WebSocket, config: Config, encoding: Encoding) {
+    tracing::debug!("established protocol: {}", encoding.protocol());
+
+    let (sender, mut receiver) = ws.split();
+
+    // Everything goes through a bounded queue to a separate writer task, so a slow client can't
+    // make the server buffer without limit or stall reading its commands
+    let (queue, queue_rx) = mpsc::channel(config.outbound_queue);
+    let (close_tx, close_rx) = oneshot::channel();
+    let mut writer = tokio::spawn(write_messages(sender, queue_rx, close_rx));
+
+    let mut counter = Counter {
+        count: 0,
+        running: true,
+        interval: DEFAULT_INTERVAL,
+    };
+    let mut ticker = tokio::time::interval(counter.interval);
+
+    let mut ping =
+        tokio::time::interval_at(Instant::now() + config.ping_interval, config.ping_interval);
+    // Set while a ping is waiting for its pong
+    let mut pong_deadline = None;
+    let mut last_seen = Instant::now();
+
+    let close = loop {
+        let outbound = tokio::select! {
+            res = receiver.next() => match res {
+                Some(Ok(message @ (Message::Text(_) | Message::Binary(_)))) => {
+                    last_seen = Instant::now();
+
+                    match encoding.decode(&message) {
+                        Ok(request) => {
+                            let interval = counter.interval;
+
+                            let reply = match counter.apply(request.command) {
+                                Ok(state) => Outbound::result(request.id, state),
+                                Err(message) => Outbound::error(request.id, INVALID_PARAMS, message),
+                            };
+
+                            if counter.interval != interval {
+                                ticker = tokio::time::interval_at(
+                                    Instant::now() + counter.interval,
+                                    counter.interval,
+                                );
+                            }
+
+                            reply
+                        }
+                        Err(reply) => reply,
+                    }
+                }
+                Some(Ok(Message::Pong(_))) => {
+                    pong_deadline = None;
+                    continue;
+                }
+                Some(Ok(Message::Close(frame))) => {
+                    tracing::info!("Client disconnected: {:?}", frame);
+                    break None;
+                }
+                // Pings are answered by axum
+                Some(Ok(msg)) => {
+                    last_seen = Instant::now();
+                    tracing::debug!("ignoring message: {:?}", msg);
+                    continue;
+                }
+                Some(Err(err)) if is_too_big(&err) => {
+                    tracing::info!("Closing connection: {}", err);
+                    break Some(close_frame(close_code::SIZE, "message too big"));
+                }
+                Some(Err(err)) => {
+                    tracing::error!("Error receiving message: {}", err);
+                    break None;
+                }
+                None => {
+                    tracing::error!("Error: message receiver stream closed");
+                    break None;
+                }
+            },
+            _ = ticker.tick(), if counter.running => {
+                let count = Outbound::Notification(Notification::Count { count: counter.count });
+                counter.count += 1;
+
+                count
+            }
+            _ = ping.tick() => {
+                // Keep the first deadline if the previous ping wasn't answered yet
+                pong_deadline.get_or_insert(Instant::now() + config.pong_timeout);
+
+                if queue.try_send(Message::Ping(Default::default())).is_err() {
+                    break Some(close_frame(close_code::AGAIN, "client too slow"));
+                }
+
+                continue;
+            }
+            _ = sleep_until(pong_deadline.unwrap_or_else(Instant::now)), if pong_deadline.is_some() => {
+                tracing::info!("Client didn't answer ping in time");
+                break Some(close_frame(close_code::AWAY, "pong timeout"));
+            }
+            _ = sleep_until(last_seen + config.idle_timeout), if !counter.running => {
+                tracing::info!("Closing idle connection");
+                break Some(close_frame(close_code::AWAY, "idle timeout"));
+            }
+            _ = &mut writer => {
+                tracing::error!("Closing connection due to write error");
+                break None;
+            }
+        };
+
+        match queue.try_send(encoding.encode(&outbound)) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                tracing::info!("Outbound queue full, dropping slow client");
+                break Some(close_frame(close_code::AGAIN, "client too slow"));
+            }
+            // The writer is gone, the select above will notice
+            Err(mpsc::error::TrySendError::Closed(_)) => {}
+        }
+    };
+
+    let closing = close.is_some();
+
+    if let Some(close) = close {
+        let _ = close_tx.send(close);
+    }
+    drop(queue);
+
+    if !writer.is_finished()
+        && tokio::time::timeout(CLOSE_TIMEOUT, &mut writer)
+            .await
+            .is_err()
+    {
+        writer.abort();
+        return;
+    }
+
+    // Wait for the client to answer the close frame. Dropping the connection right away resets
+    // it if the client still sends something, e.g. a pong, and the client never sees the close
+    if closing {
+        let _ = tokio::time::timeout(CLOSE_TIMEOUT, async {
+            while let Some(Ok(message)) = receiver.next().await {
+                if let Message::Close(_) = message {
+                    break;
+                }
+            }
+        })
+        .await;
+    }
+}
+
+// Frames over `max_frame_size` and messages over `max_message_size` are reported as capacity errors
+fn is_too_big(err: &axum::Error) -> bool {
+    std::error::Error::source(err)
+        .and_then(|source| source.downcast_ref::<tungstenite::Error>())
+        .is_some_and(|err| matches!(err, tungstenite::Error::Capacity(_)))
+}
+
+async fn write_messages(
+    mut sender: SplitSink<WebSocket, Message>,
+    mut queue: mpsc::Receiver<Message>,
+    mut close: oneshot::Receiver<CloseFrame>,
+) {
+    loop {
+        tokio::select! {
+            // A close skips whatever is still queued
+            biased;
+
+            frame = &mut close => {
+                if let Ok(frame) = frame {
+                    let _ = sender.send(Message::Close(Some(frame))).await;
+                }
+                break;
+            }
+            message = queue.recv() => {
+                let Some(message) = message else {
+                    break;
+                };
+
+                if let Err(err) = sender.send(message).await {
+                    tracing::error!("Error sending message {}", err);
+                    break;
+                }
+            }
+        }
+    }
+}
@@ -1,15 +1,17 @@
+mod connection;
+mod protocol;
+
 use std::time::Duration;
 
 use axum::{
-    extract::{
-        ws::{Message, WebSocket},
-        WebSocketUpgrade,
-    },
-    response::Response,
+    extract::{State, WebSocketUpgrade},
+    http::StatusCode,
+    response::{IntoResponse, Response},
     routing::get,
     Router,
 };
-use futures::{stream::SplitSink, SinkExt, StreamExt};
+use connection::Config;
+use protocol::Encoding;
 use tokio::net::TcpListener;
 use tower_http::services::ServeDir;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
@@ -24,14 +26,7 @@ async fn main() {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let serve_dir = ServeDir::new("assets").append_index_html_on_directories(true);
-
-    // let (sender, _) = broadcast::channel::<String>(32);
-
-    let app = Router::new()
-        .route_service("/", serve_dir)
-        .route("/ws", get(ws_handler));
-    // .with_state(sender);
+    let app = app(config_from_env());
 
     let listener = TcpListener::bind("localhost:3000").await.unwrap();
 
@@ -40,71 +35,309 @@ async fn main() {
     axum::serve(listener, app).await.unwrap();
 }
 
-async fn ws_handler(ws: WebSocketUpgrade) -> Response {
-    ws.on_upgrade(|ws| handle_ws(ws))
+fn app(config: Config) -> Router {
+    let serve_dir = ServeDir::new("assets").append_index_html_on_directories(true);
+
+    Router::new()
+        .route_service("/", serve_dir)
+        .route("/ws", get(ws_handler))
+        .with_state(config)
 }
 
-async fn handle_ws(mut ws: WebSocket) {
-    if let Err(err) = ws.send(Message::Text("Hi from server".into())).await {
-        tracing::error!("Error sending message {}", err);
-        return;
+// Every timeout can be overridden with an environment variable holding seconds
+fn config_from_env() -> Config {
+    fn secs(name: &str, default: Duration) -> Duration {
+        std::env::var(name)
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .map(Duration::from_secs_f64)
+            .unwrap_or(default)
     }
 
-    let protocol = ws.protocol();
-
-    tracing::debug!("established protocol: {:?}", protocol);
-
-    let (mut sender, mut receiver) = ws.split();
-
-    let mut count = 0;
-
-    loop {
-        tokio::select! {
-            res = receiver.next() => {
-                match res {
-                    Some(Ok(msg)) => {
-                        println!("received: {:?}", msg);
-
-                        if let Message::Close(frame) = msg {
-                            tracing::info!("Client disconnected: {:?}", frame);
-                            break;
-                        }
-                    }
-                    Some(Err(err)) => {
-                        tracing::error!("Error receiving message: {}", err);
-                        break;
-                    }
-                    None => {
-                        tracing::error!("Error: message receiver stream closed");
-                        break;
-
-                    }
-                }
-            },
-            res = send_count(&mut sender, &mut count) => {
-                if let Err(err) = res {
-                    // Break the loop if there is an error
-                    tracing::error!("Closing connection due to error: {}", err);
-                    break;
-                }
-            }
-        }
+    let default = Config::default();
+
+    Config {
+        ping_interval: secs("WS_PING_INTERVAL", default.ping_interval),
+        pong_timeout: secs("WS_PONG_TIMEOUT", default.pong_timeout),
+        idle_timeout: secs("WS_IDLE_TIMEOUT", default.idle_timeout),
+        outbound_queue: std::env::var("WS_OUTBOUND_QUEUE")
+            .ok()
+            .and_then(|size| size.parse().ok())
+            .unwrap_or(default.outbound_queue),
+        ..default
     }
 }
 
-async fn send_count(
-    sender: &mut SplitSink<WebSocket, Message>,
-    count: &mut i32,
-) -> Result<(), String> {
-    if let Err(err) = sender
-        .send(Message::Text(format!("{}", count).into()))
-        .await
-    {
-        tracing::error!("Error sending message {} {}", err, count);
-        return Err(err.to_string());
+async fn ws_handler(State(config): State<Config>, ws: WebSocketUpgrade) -> Response {
+    // axum doesn't support the permessage-deflate extension, clients that want smaller messages
+    // can pick one of the binary encodings instead
+    let ws = ws
+        .protocols(Encoding::ALL.map(Encoding::protocol))
+        .max_frame_size(config.max_frame_size)
+        .max_message_size(config.max_message_size);
+
+    // Clients that don't speak our protocol couldn't make sense of anything we send
+    let Some(encoding) = ws
+        .selected_protocol()
+        .and_then(|protocol| protocol.to_str().ok())
+        .and_then(Encoding::from_protocol)
+    else {
+        let protocols = Encoding::ALL.map(Encoding::protocol).join(", ");
+
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("Sec-WebSocket-Protocol must include one of {protocols}"),
+        )
+            .into_response();
+    };
+
+    ws.on_upgrade(move |ws| connection::handle_ws(ws, config, encoding))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use tungstenite::{
+        http::StatusCode,
+        protocol::frame::{
+            coding::{Data, OpCode},
+            Frame,
+        },
+        Error,
     };
+    use ws_harness::{CloseCode, Message, TestServer, WsClient};
+
+    use super::*;
+
+    fn encode(encoding: Encoding, value: &Value) -> Message {
+        match encoding {
+            Encoding::Json => Message::text(value.to_string()),
+            Encoding::MessagePack => Message::binary(rmp_serde::to_vec_named(value).unwrap()),
+            Encoding::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(value, &mut bytes).unwrap();
+                Message::binary(bytes)
+            }
+        }
+    }
+
+    fn decode(encoding: Encoding, message: Message) -> Value {
+        match (encoding, message) {
+            (Encoding::Json, Message::Text(text)) => serde_json::from_str(&text).unwrap(),
+            (Encoding::MessagePack, Message::Binary(bytes)) => {
+                rmp_serde::from_slice(&bytes).unwrap()
+            }
+            (Encoding::Cbor, Message::Binary(bytes)) => ciborium::from_reader(&bytes[..]).unwrap(),
+            (encoding, message) => panic!("unexpected {message:?} for {encoding:?}"),
+        }
+    }
+
+    // Next response, skipping the counter notifications in between
+    async fn response(client: &mut WsClient, encoding: Encoding) -> Value {
+        loop {
+            let value = decode(encoding, client.expect_message().await);
+
+            if value.get("id").is_some() {
+                return value;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_counter_stream() {
+        let server = TestServer::spawn(app(Config::default())).await;
+        let mut client = server
+            .connect_with_protocol("/ws", Encoding::Json.protocol())
+            .await;
+
+        assert_eq!(client.protocol(), Some(Encoding::Json.protocol()));
+
+        // The counter starts right away and counts up from zero
+        for count in 0..3 {
+            let message = decode(Encoding::Json, client.expect_message().await);
+            assert_eq!(
+                message,
+                json!({ "method": "count", "params": { "count": count } })
+            );
+        }
+
+        client.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_counter_commands() {
+        let server = TestServer::spawn(app(Config::default())).await;
+
+        for encoding in Encoding::ALL {
+            let mut client = server
+                .connect_with_protocol("/ws", encoding.protocol())
+                .await;
+
+            client
+                .send(encode(
+                    encoding,
+                    &json!({ "id": 7, "method": "set_interval", "params": { "interval_ms": 100 } }),
+                ))
+                .await;
 
-    *count += 1;
-    tokio::time::sleep(Duration::from_millis(300)).await;
-    Ok(())
+            let reply = response(&mut client, encoding).await;
+            assert_eq!(
+                reply,
+                json!({ "id": 7, "result": { "running": true, "interval_ms": 100 } })
+            );
+
+            client
+                .send(encode(encoding, &json!({ "id": "stop", "method": "stop" })))
+                .await;
+
+            let reply = response(&mut client, encoding).await;
+            assert_eq!(reply["id"], "stop");
+            assert_eq!(reply["result"]["running"], false);
+
+            client
+                .send(encode(encoding, &json!({ "id": 8, "method": "jump" })))
+                .await;
+
+            let reply = response(&mut client, encoding).await;
+            assert_eq!(reply["id"], 8);
+            assert_eq!(reply["error"]["code"], protocol::METHOD_NOT_FOUND);
+            assert_eq!(reply["error"]["message"], "unknown method `jump`");
+
+            // A known method with the wrong params is a malformed request
+            client
+                .send(encode(
+                    encoding,
+                    &json!({ "id": 9, "method": "set_interval", "params": { "ms": 100 } }),
+                ))
+                .await;
+
+            let reply = response(&mut client, encoding).await;
+            assert_eq!(reply["id"], 9);
+            assert_eq!(reply["error"]["code"], protocol::INVALID_REQUEST);
+
+            client
+                .send(encode(encoding, &json!({ "id": 10, "method": "start" })))
+                .await;
+
+            let reply = response(&mut client, encoding).await;
+            assert_eq!(reply["id"], 10);
+            assert_eq!(reply["result"]["running"], true);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let config = Config {
+            idle_timeout: Duration::from_millis(200),
+            ..Config::default()
+        };
+        let server = TestServer::spawn(app(config)).await;
+        let mut client = server
+            .connect_with_protocol("/ws", Encoding::Json.protocol())
+            .await;
+
+        client
+            .send(encode(
+                Encoding::Json,
+                &json!({ "id": 1, "method": "stop" }),
+            ))
+            .await;
+        response(&mut client, Encoding::Json).await;
+
+        client.expect_close(CloseCode::Away).await;
+    }
+
+    #[tokio::test]
+    async fn test_pong_timeout() {
+        let config = Config {
+            ping_interval: Duration::from_millis(100),
+            pong_timeout: Duration::from_millis(200),
+            ..Config::default()
+        };
+        let server = TestServer::spawn(app(config)).await;
+        let mut client = server
+            .connect_with_protocol("/ws", Encoding::Json.protocol())
+            .await;
+
+        // Pings are answered while reading, for longer than the pong timeout
+        for _ in 0..3 {
+            client.expect_message().await;
+        }
+
+        // Without reading, the pings go unanswered
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        client.expect_close(CloseCode::Away).await;
+    }
+
+    #[tokio::test]
+    async fn test_slow_client() {
+        let config = Config {
+            outbound_queue: 1,
+            ..Config::default()
+        };
+        let server = TestServer::spawn(app(config)).await;
+        let mut client = server
+            .connect_with_protocol("/ws", Encoding::Json.protocol())
+            .await;
+
+        // Replies echo the id, so big ids fill up the socket buffers and then the queue quickly
+        let request = json!({ "id": "x".repeat(8 << 10), "method": "start" });
+        let request = Message::text(request.to_string());
+
+        // Never reading a reply, until the server drops the connection
+        let flood = async { while client.try_send(request.clone()).await.is_ok() {} };
+
+        tokio::time::timeout(Duration::from_secs(10), flood)
+            .await
+            .expect("slow client should be dropped");
+    }
+
+    #[tokio::test]
+    async fn test_frame_too_big() {
+        let server = TestServer::spawn(app(Config::default())).await;
+        let mut client = server
+            .connect_with_protocol("/ws", Encoding::Json.protocol())
+            .await;
+
+        let huge = "x".repeat(Config::default().max_frame_size + 1);
+        client.send_text(huge).await;
+
+        client.expect_close(CloseCode::Size).await;
+    }
+
+    #[tokio::test]
+    async fn test_message_too_big() {
+        let config = Config::default();
+        let server = TestServer::spawn(app(config)).await;
+        let mut client = server
+            .connect_with_protocol("/ws", Encoding::Json.protocol())
+            .await;
+
+        // Every fragment is well within `max_frame_size`, only the whole message is too big
+        let fragment = "x".repeat(config.max_frame_size / 2);
+        let fragments = config.max_message_size / fragment.len() + 1;
+
+        for i in 0..fragments {
+            let opcode = match i {
+                0 => OpCode::Data(Data::Text),
+                _ => OpCode::Data(Data::Continue),
+            };
+            let frame = Frame::message(fragment.clone(), opcode, i == fragments - 1);
+
+            client.send(Message::Frame(frame)).await;
+        }
+
+        client.expect_close(CloseCode::Size).await;
+    }
+
+    #[tokio::test]
+    async fn test_unknown_protocol() {
+        let server = TestServer::spawn(app(Config::default())).await;
+
+        match server.try_connect("/ws", Some("graphql-ws")).await {
+            Err(Error::Http(response)) => assert_eq!(response.status(), StatusCode::BAD_REQUEST),
+            other => panic!("expected a rejected upgrade, got {:?}", other.map(|_| ())),
+        }
+    }
 }
@@ -0,0 +1,188 @@
+//! JSON-RPC style messages of the counter protocol.
+//!
+//! Clients send requests like `{"id": 1, "method": "set_interval", "params": {"interval_ms": 500}}`
+//! and get a response with the same `id`, holding either a `result` or an `error`. The counter
+//! itself is pushed as `{"method": "count", "params": {"count": 42}}` notifications, which have
+//! no `id`.
+//!
+//! The same messages can be encoded as JSON in text frames, or as MessagePack or CBOR in binary
+//! frames. Which one is used depends on the subprotocol the client asked for.
+
+use axum::extract::ws::Message;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// How messages are encoded on a connection, picked by subprotocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl Encoding {
+    /// In order of preference, for when a client offers more than one.
+    pub const ALL: [Encoding; 3] = [Encoding::MessagePack, Encoding::Cbor, Encoding::Json];
+
+    /// The subprotocol clients have to ask for in `Sec-WebSocket-Protocol`.
+    pub fn protocol(self) -> &'static str {
+        match self {
+            Encoding::Json => "counter.v1.json",
+            Encoding::MessagePack => "counter.v1.msgpack",
+            Encoding::Cbor => "counter.v1.cbor",
+        }
+    }
+
+    pub fn from_protocol(protocol: &str) -> Option<Self> {
+        Encoding::ALL
+            .into_iter()
+            .find(|encoding| encoding.protocol() == protocol)
+    }
+
+    pub fn encode(self, message: &Outbound) -> Message {
+        match self {
+            Encoding::Json => {
+                Message::text(serde_json::to_string(message).expect("messages serialize to JSON"))
+            }
+            // Named, so structs become maps like in JSON instead of arrays
+            Encoding::MessagePack => Message::binary(
+                rmp_serde::to_vec_named(message).expect("messages serialize to MessagePack"),
+            ),
+            Encoding::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(message, &mut bytes).expect("messages serialize to CBOR");
+                Message::binary(bytes)
+            }
+        }
+    }
+
+    /// Decodes a text or binary frame, or returns the error response to send instead.
+    pub fn decode(self, message: &Message) -> Result<Request, Outbound> {
+        let value = match (self, message) {
+            (Encoding::Json, Message::Text(text)) => serde_json::from_str::<Value>(text)
+                .map_err(|err| Outbound::error(Value::Null, PARSE_ERROR, err.to_string()))?,
+            (Encoding::MessagePack, Message::Binary(bytes)) => {
+                rmp_serde::from_slice::<Value>(bytes)
+                    .map_err(|err| Outbound::error(Value::Null, PARSE_ERROR, err.to_string()))?
+            }
+            (Encoding::Cbor, Message::Binary(bytes)) => {
+                ciborium::from_reader::<Value, _>(&bytes[..])
+                    .map_err(|err| Outbound::error(Value::Null, PARSE_ERROR, err.to_string()))?
+            }
+            (Encoding::Json, _) => {
+                return Err(Outbound::error(
+                    Value::Null,
+                    INVALID_REQUEST,
+                    "expected a text frame",
+                ))
+            }
+            (_, _) => {
+                return Err(Outbound::error(
+                    Value::Null,
+                    INVALID_REQUEST,
+                    "expected a binary frame",
+                ))
+            }
+        };
+
+        parse_request(value)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Request {
+    // Any JSON value, echoed back as is
+    pub id: Value,
+    #[serde(flatten)]
+    pub command: Command,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "method", content = "params", rename_all = "snake_case")]
+pub enum Command {
+    Start,
+    Stop,
+    SetInterval { interval_ms: u64 },
+}
+
+impl Command {
+    // As they are named on the wire
+    const METHODS: [&'static str; 3] = ["start", "stop", "set_interval"];
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum Outbound {
+    Response {
+        id: Value,
+        #[serde(flatten)]
+        outcome: Outcome,
+    },
+    Notification(Notification),
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Result(CounterState),
+    Error(RpcError),
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "method", content = "params", rename_all = "snake_case")]
+pub enum Notification {
+    Count { count: u64 },
+}
+
+/// Sent back for every successful command.
+#[derive(Debug, Clone, Serialize)]
+pub struct CounterState {
+    pub running: bool,
+    pub interval_ms: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RpcError {
+    pub code: i32,
+    pub message: String,
+}
+
+// Same codes as JSON-RPC 2.0
+pub const PARSE_ERROR: i32 = -32700;
+pub const INVALID_REQUEST: i32 = -32600;
+pub const METHOD_NOT_FOUND: i32 = -32601;
+pub const INVALID_PARAMS: i32 = -32602;
+
+impl Outbound {
+    pub fn result(id: Value, state: CounterState) -> Self {
+        Outbound::Response {
+            id,
+            outcome: Outcome::Result(state),
+        }
+    }
+
+    pub fn error(id: Value, code: i32, message: impl Into<String>) -> Self {
+        Outbound::Response {
+            id,
+            outcome: Outcome::Error(RpcError {
+                code,
+                message: message.into(),
+            }),
+        }
+    }
+}
+
+fn parse_request(value: Value) -> Result<Request, Outbound> {
+    // Keep the id if there is one, so even a bad request can be correlated
+    let id = value.get("id").cloned().unwrap_or(Value::Null);
+
+    if let Some(method) = value.get("method").and_then(Value::as_str) {
+        if !Command::METHODS.contains(&method) {
+            let message = format!("unknown method `{method}`");
+            return Err(Outbound::error(id, METHOD_NOT_FOUND, message));
+        }
+    }
+
+    serde_json::from_value(value)
+        .map_err(|err| Outbound::error(id, INVALID_REQUEST, err.to_string()))
+}
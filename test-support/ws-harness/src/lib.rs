@@ -0,0 +1,193 @@
+//! Helpers for testing WebSocket endpoints of the examples end to end.
+//!
+//! ```ignore
+//! let server = TestServer::spawn(app()).await;
+//! let mut client = server.connect("/ws").await;
+//!
+//! client.send_text("alice").await;
+//! client.expect_text("alice joined the chat").await;
+//! ```
+//!
+//! Every `expect_*` helper waits at most [`DEFAULT_TIMEOUT`] (see [`WsClient::with_timeout`]) and
+//! panics with what it got instead, so a broken server fails the test rather than hanging it.
+
+use std::{net::SocketAddr, time::Duration};
+
+use axum::Router;
+use futures::{SinkExt, StreamExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{self, client::IntoClientRequest, http::Response},
+    MaybeTlsStream, WebSocketStream,
+};
+
+pub use tokio_tungstenite::tungstenite::{protocol::frame::coding::CloseCode, Message};
+
+/// How long the `expect_*` helpers wait for a frame by default.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A router served on an ephemeral port, for as long as the test runs.
+pub struct TestServer {
+    addr: SocketAddr,
+}
+
+impl TestServer {
+    pub async fn spawn(app: Router) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        TestServer { addr }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("ws://{}{path}", self.addr)
+    }
+
+    /// Connects to `path`, panicking if the upgrade fails.
+    pub async fn connect(&self, path: &str) -> WsClient {
+        self.try_connect(path, None).await.unwrap()
+    }
+
+    /// Connects to `path` asking for the given `Sec-WebSocket-Protocol`.
+    pub async fn connect_with_protocol(&self, path: &str, protocol: &str) -> WsClient {
+        self.try_connect(path, Some(protocol)).await.unwrap()
+    }
+
+    /// Like [`TestServer::connect`], but returns the error of a rejected upgrade so tests can
+    /// look at it.
+    pub async fn try_connect(
+        &self,
+        path: &str,
+        protocol: Option<&str>,
+    ) -> Result<WsClient, tungstenite::Error> {
+        let mut request = self.url(path).into_client_request()?;
+
+        if let Some(protocol) = protocol {
+            request
+                .headers_mut()
+                .insert("sec-websocket-protocol", protocol.parse().unwrap());
+        }
+
+        let (stream, response) = connect_async(request).await?;
+
+        Ok(WsClient {
+            stream,
+            response: response.map(|_| ()),
+            timeout: DEFAULT_TIMEOUT,
+        })
+    }
+}
+
+/// A connected client with assertions for what the server sends.
+pub struct WsClient {
+    stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    response: Response<()>,
+    timeout: Duration,
+}
+
+impl WsClient {
+    /// Changes how long the `expect_*` helpers wait.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The subprotocol the server picked, if any.
+    pub fn protocol(&self) -> Option<&str> {
+        self.response
+            .headers()
+            .get("sec-websocket-protocol")
+            .and_then(|protocol| protocol.to_str().ok())
+    }
+
+    pub async fn send(&mut self, message: Message) {
+        self.try_send(message).await.unwrap();
+    }
+
+    /// Like [`WsClient::send`], but returns the error instead of panicking, e.g. once the server
+    /// dropped the connection.
+    pub async fn try_send(&mut self, message: Message) -> Result<(), tungstenite::Error> {
+        self.stream.send(message).await
+    }
+
+    pub async fn send_text(&mut self, text: impl Into<String>) {
+        self.send(Message::text(text.into())).await;
+    }
+
+    pub async fn send_binary(&mut self, bytes: impl Into<Vec<u8>>) {
+        self.send(Message::binary(bytes.into())).await;
+    }
+
+    /// Next frame from the server, `None` once the connection is gone.
+    ///
+    /// Pings and pongs are skipped, tungstenite answers pings by itself.
+    pub async fn recv(&mut self) -> Option<Message> {
+        let timeout = self.timeout;
+
+        let next = async {
+            loop {
+                match self.stream.next().await {
+                    Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+                    Some(Ok(message)) => return Some(message),
+                    // The server may drop the connection without a close handshake
+                    Some(Err(_)) | None => return None,
+                }
+            }
+        };
+
+        tokio::time::timeout(timeout, next)
+            .await
+            .unwrap_or_else(|_| panic!("no frame from the server within {timeout:?}"))
+    }
+
+    /// Next frame, which has to be a data frame.
+    pub async fn expect_message(&mut self) -> Message {
+        match self.recv().await {
+            Some(message @ (Message::Text(_) | Message::Binary(_))) => message,
+            other => panic!("expected a data frame, got {other:?}"),
+        }
+    }
+
+    /// Next frame, which has to be this text.
+    pub async fn expect_text(&mut self, expected: &str) {
+        match self.recv().await {
+            Some(Message::Text(text)) => assert_eq!(text.as_str(), expected),
+            other => panic!("expected text {expected:?}, got {other:?}"),
+        }
+    }
+
+    /// Skips data frames until the server closes the connection with `code`.
+    pub async fn expect_close(&mut self, code: CloseCode) {
+        loop {
+            match self.recv().await {
+                Some(Message::Close(Some(frame))) => {
+                    assert_eq!(frame.code, code, "closed with {:?}", frame.reason);
+                    return;
+                }
+                Some(Message::Close(None)) => panic!("expected close code {code}, got none"),
+                Some(_) => continue,
+                None => panic!("expected close code {code}, connection dropped without one"),
+            }
+        }
+    }
+
+    /// Expects the connection to end next, with or without a close frame.
+    pub async fn expect_closed(&mut self) {
+        match self.recv().await {
+            Some(Message::Close(_)) | None => {}
+            Some(other) => panic!("expected the connection to close, got {other:?}"),
+        }
+    }
+
+    /// Closes the connection from the client side.
+    pub async fn close(mut self) {
+        let _ = self.stream.close(None).await;
+    }
+}